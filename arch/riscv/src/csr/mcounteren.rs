@@ -0,0 +1,15 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2022.
+
+use kernel::utilities::registers::register_bitfields;
+
+// mcounteren controls which counters the next lower privilege mode may read.
+register_bitfields![usize,
+    pub mcounteren [
+        cy OFFSET(0) NUMBITS(1) [],
+        tm OFFSET(1) NUMBITS(1) [],
+        ir OFFSET(2) NUMBITS(1) [],
+        hpm OFFSET(3) NUMBITS(29) []
+    ]
+];
@@ -0,0 +1,13 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2022.
+
+use kernel::utilities::registers::register_bitfields;
+
+// medeleg selects the exceptions delegated to S-mode. It only exists on
+// harts that implement S-mode.
+register_bitfields![usize,
+    pub medeleg [
+        exceptions OFFSET(0) NUMBITS(crate::XLEN) []
+    ]
+];
@@ -0,0 +1,20 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2022.
+
+use kernel::utilities::registers::register_bitfields;
+
+// misa reports the base ISA and which standard extensions are implemented.
+// A value of zero means the hart does not report this information.
+register_bitfields![usize,
+    pub misa [
+        a OFFSET(0) NUMBITS(1) [],
+        c OFFSET(2) NUMBITS(1) [],
+        e OFFSET(4) NUMBITS(1) [],
+        i OFFSET(8) NUMBITS(1) [],
+        m OFFSET(12) NUMBITS(1) [],
+        s OFFSET(18) NUMBITS(1) [],
+        u OFFSET(20) NUMBITS(1) [],
+        extensions OFFSET(0) NUMBITS(26) []
+    ]
+];
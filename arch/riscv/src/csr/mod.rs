@@ -5,28 +5,33 @@
 //! Tock Register interface for using CSR registers.
 
 use riscv_csr::csr::{
-    ReadWriteRiscvCsr, MCAUSE, MCYCLE, MCYCLEH, MEPC, MIE, MINSTRET, MINSTRETH, MIP, MSCRATCH,
-    MSECCFG, MSECCFGH, MSTATUS, MTVAL, MTVEC, PMPADDR0, PMPADDR1, PMPADDR10, PMPADDR11, PMPADDR12,
-    PMPADDR13, PMPADDR14, PMPADDR15, PMPADDR16, PMPADDR17, PMPADDR18, PMPADDR19, PMPADDR2,
-    PMPADDR20, PMPADDR21, PMPADDR22, PMPADDR23, PMPADDR24, PMPADDR25, PMPADDR26, PMPADDR27,
-    PMPADDR28, PMPADDR29, PMPADDR3, PMPADDR30, PMPADDR31, PMPADDR32, PMPADDR33, PMPADDR34,
-    PMPADDR35, PMPADDR36, PMPADDR37, PMPADDR38, PMPADDR39, PMPADDR4, PMPADDR40, PMPADDR41,
-    PMPADDR42, PMPADDR43, PMPADDR44, PMPADDR45, PMPADDR46, PMPADDR47, PMPADDR48, PMPADDR49,
-    PMPADDR5, PMPADDR50, PMPADDR51, PMPADDR52, PMPADDR53, PMPADDR54, PMPADDR55, PMPADDR56,
-    PMPADDR57, PMPADDR58, PMPADDR59, PMPADDR6, PMPADDR60, PMPADDR61, PMPADDR62, PMPADDR63,
-    PMPADDR7, PMPADDR8, PMPADDR9, PMPCFG0, PMPCFG1, PMPCFG10, PMPCFG11, PMPCFG12, PMPCFG13,
-    PMPCFG14, PMPCFG15, PMPCFG2, PMPCFG3, PMPCFG4, PMPCFG5, PMPCFG6, PMPCFG7, PMPCFG8, PMPCFG9,
-    STVEC, UTVEC,
+    ReadWriteRiscvCsr, MCAUSE, MCOUNTEREN, MCOUNTINHIBIT, MCYCLE, MCYCLEH, MEDELEG, MEPC, MIDELEG,
+    MIE, MINSTRET, MINSTRETH, MIP, MISA, MSCRATCH, MSECCFG, MSECCFGH, MSTATUS, MTVAL, MTVEC,
+    PMPADDR0, PMPADDR1, PMPADDR10, PMPADDR11, PMPADDR12, PMPADDR13, PMPADDR14, PMPADDR15,
+    PMPADDR16, PMPADDR17, PMPADDR18, PMPADDR19, PMPADDR2, PMPADDR20, PMPADDR21, PMPADDR22,
+    PMPADDR23, PMPADDR24, PMPADDR25, PMPADDR26, PMPADDR27, PMPADDR28, PMPADDR29, PMPADDR3,
+    PMPADDR30, PMPADDR31, PMPADDR32, PMPADDR33, PMPADDR34, PMPADDR35, PMPADDR36, PMPADDR37,
+    PMPADDR38, PMPADDR39, PMPADDR4, PMPADDR40, PMPADDR41, PMPADDR42, PMPADDR43, PMPADDR44,
+    PMPADDR45, PMPADDR46, PMPADDR47, PMPADDR48, PMPADDR49, PMPADDR5, PMPADDR50, PMPADDR51,
+    PMPADDR52, PMPADDR53, PMPADDR54, PMPADDR55, PMPADDR56, PMPADDR57, PMPADDR58, PMPADDR59,
+    PMPADDR6, PMPADDR60, PMPADDR61, PMPADDR62, PMPADDR63, PMPADDR7, PMPADDR8, PMPADDR9, PMPCFG0,
+    PMPCFG1, PMPCFG10, PMPCFG11, PMPCFG12, PMPCFG13, PMPCFG14, PMPCFG15, PMPCFG2, PMPCFG3, PMPCFG4,
+    PMPCFG5, PMPCFG6, PMPCFG7, PMPCFG8, PMPCFG9, STVEC, UTVEC,
 };
 use tock_registers::fields::FieldValue;
 use tock_registers::interfaces::{ReadWriteable, Readable, Writeable};
 
 pub mod mcause;
+pub mod mcounteren;
+pub mod mcountinhibit;
 pub mod mcycle;
+pub mod medeleg;
 pub mod mepc;
+pub mod mideleg;
 pub mod mie;
 pub mod minstret;
 pub mod mip;
+pub mod misa;
 pub mod mscratch;
 pub mod mseccfg;
 pub mod mstatus;
@@ -150,6 +155,14 @@ pub struct CSR {
     pub mip: ReadWriteRiscvCsr<usize, mip::mip::Register, MIP>,
     pub mtvec: ReadWriteRiscvCsr<usize, mtvec::mtvec::Register, MTVEC>,
     pub mstatus: ReadWriteRiscvCsr<usize, mstatus::mstatus::Register, MSTATUS>,
+    pub misa: ReadWriteRiscvCsr<usize, misa::misa::Register, MISA>,
+    pub mcounteren: ReadWriteRiscvCsr<usize, mcounteren::mcounteren::Register, MCOUNTEREN>,
+    pub mcountinhibit:
+        ReadWriteRiscvCsr<usize, mcountinhibit::mcountinhibit::Register, MCOUNTINHIBIT>,
+
+    // Only present on harts that implement S-mode, check `misa` first.
+    pub medeleg: ReadWriteRiscvCsr<usize, medeleg::medeleg::Register, MEDELEG>,
+    pub mideleg: ReadWriteRiscvCsr<usize, mideleg::mideleg::Register, MIDELEG>,
 
     pub mseccfg: ReadWriteRiscvCsr<usize, mseccfg::mseccfg::Register, MSECCFG>,
     #[cfg(any(target_arch = "riscv32", not(target_os = "none")))]
@@ -267,6 +280,12 @@ pub const CSR: &CSR = &CSR {
     mip: ReadWriteRiscvCsr::new(),
     mtvec: ReadWriteRiscvCsr::new(),
     mstatus: ReadWriteRiscvCsr::new(),
+    misa: ReadWriteRiscvCsr::new(),
+    mcounteren: ReadWriteRiscvCsr::new(),
+    mcountinhibit: ReadWriteRiscvCsr::new(),
+
+    medeleg: ReadWriteRiscvCsr::new(),
+    mideleg: ReadWriteRiscvCsr::new(),
 
     mseccfg: ReadWriteRiscvCsr::new(),
     #[cfg(any(target_arch = "riscv32", not(target_os = "none")))]
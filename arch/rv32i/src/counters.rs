@@ -0,0 +1,162 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2022.
+
+//! Machine-mode hardware performance counters.
+//!
+//! `mcycle` and `minstret` are 64-bit counters, which a 32-bit core reads as
+//! two CSRs each. [`read_cycle`] and [`read_instret`] combine the halves
+//! without tearing when the low half rolls over between the two reads.
+//!
+//! [`inhibit_counters`] and [`enable_counters`] stop and restart counters
+//! through `mcountinhibit`, so a measured region can be frozen without
+//! reading the counters around it:
+//!
+//! ```rust,no_run
+//! use rv32i::counters::{self, COUNTER_CYCLE, COUNTER_INSTRET};
+//!
+//! fn measure<F: FnOnce()>(f: F) -> (u64, u64) {
+//!     let (cycles, instret) = (counters::read_cycle(), counters::read_instret());
+//!     counters::enable_counters(COUNTER_CYCLE | COUNTER_INSTRET);
+//!     f();
+//!     counters::inhibit_counters(COUNTER_CYCLE | COUNTER_INSTRET);
+//!     (
+//!         counters::read_cycle() - cycles,
+//!         counters::read_instret() - instret,
+//!     )
+//! }
+//! ```
+
+#[cfg(all(target_arch = "riscv32", target_os = "none"))]
+use crate::csr::CSR;
+#[cfg(all(target_arch = "riscv32", target_os = "none"))]
+use kernel::utilities::registers::interfaces::Readable;
+
+/// `mcountinhibit` bit for the `mcycle` counter.
+pub const COUNTER_CYCLE: u32 = 1 << 0;
+/// `mcountinhibit` bit for the `minstret` counter.
+pub const COUNTER_INSTRET: u32 = 1 << 2;
+
+/// Combine a 64-bit counter read as two 32-bit halves.
+///
+/// The high half is read before and after the low half. If it changed, the
+/// low half rolled over in between and the read is retried, which should
+/// only ever happen once.
+pub fn read_split_counter<H, L>(mut read_high: H, mut read_low: L) -> u64
+where
+    H: FnMut() -> u32,
+    L: FnMut() -> u32,
+{
+    loop {
+        let high = read_high();
+        let low = read_low();
+        if high == read_high() {
+            return ((high as u64) << 32) | low as u64;
+        }
+    }
+}
+
+/// Number of clock cycles executed by the core (`mcycle`).
+#[cfg(all(target_arch = "riscv32", target_os = "none"))]
+pub fn read_cycle() -> u64 {
+    read_split_counter(|| CSR.mcycleh.get() as u32, || CSR.mcycle.get() as u32)
+}
+
+/// Number of instructions retired by the core (`minstret`).
+#[cfg(all(target_arch = "riscv32", target_os = "none"))]
+pub fn read_instret() -> u64 {
+    read_split_counter(|| CSR.minstreth.get() as u32, || CSR.minstret.get() as u32)
+}
+
+/// Stop the counters selected by `mask` from incrementing.
+///
+/// `mask` is a combination of [`COUNTER_CYCLE`], [`COUNTER_INSTRET`] and the
+/// `mhpmcounter` bits of `mcountinhibit`. Other counters are left as they are.
+#[cfg(all(target_arch = "riscv32", target_os = "none"))]
+pub fn inhibit_counters(mask: u32) {
+    CSR.mcountinhibit.read_and_set_bits(mask as usize);
+}
+
+/// Let the counters selected by `mask` increment again.
+///
+/// This undoes [`inhibit_counters`] for the same `mask`.
+#[cfg(all(target_arch = "riscv32", target_os = "none"))]
+pub fn enable_counters(mask: u32) {
+    CSR.mcountinhibit.read_and_clear_bits(mask as usize);
+}
+
+// Mock implementations for tests on Travis-CI.
+#[cfg(not(any(target_arch = "riscv32", target_os = "none")))]
+/// Number of clock cycles executed by the core (mock)
+pub fn read_cycle() -> u64 {
+    unimplemented!()
+}
+
+#[cfg(not(any(target_arch = "riscv32", target_os = "none")))]
+/// Number of instructions retired by the core (mock)
+pub fn read_instret() -> u64 {
+    unimplemented!()
+}
+
+#[cfg(not(any(target_arch = "riscv32", target_os = "none")))]
+/// Stop the counters selected by `mask` from incrementing (mock)
+pub fn inhibit_counters(_mask: u32) {
+    unimplemented!()
+}
+
+#[cfg(not(any(target_arch = "riscv32", target_os = "none")))]
+/// Let the counters selected by `mask` increment again (mock)
+pub fn enable_counters(_mask: u32) {
+    unimplemented!()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+
+    /// A 64-bit counter that advances by `step` on every CSR read.
+    struct SimulatedCounter {
+        value: Cell<u64>,
+        step: u64,
+    }
+
+    impl SimulatedCounter {
+        fn new(value: u64, step: u64) -> Self {
+            SimulatedCounter {
+                value: Cell::new(value),
+                step,
+            }
+        }
+
+        fn tick(&self) -> u64 {
+            let value = self.value.get();
+            self.value.set(value.wrapping_add(self.step));
+            value
+        }
+
+        fn read_high(&self) -> u32 {
+            (self.tick() >> 32) as u32
+        }
+
+        fn read_low(&self) -> u32 {
+            self.tick() as u32
+        }
+    }
+
+    #[test]
+    fn split_counter_without_rollover() {
+        let counter = SimulatedCounter::new(0x1_0000_1000, 1);
+        let value = read_split_counter(|| counter.read_high(), || counter.read_low());
+        assert_eq!(value, 0x1_0000_1001);
+    }
+
+    #[test]
+    fn split_counter_rereads_after_rollover() {
+        // The low half wraps between the first high read and the low read.
+        let counter = SimulatedCounter::new(0x1_FFFF_FFFF, 1);
+        let value = read_split_counter(|| counter.read_high(), || counter.read_low());
+        // The torn read would have been 0x1_0000_0000.
+        assert_eq!(value, 0x2_0000_0003);
+    }
+}
@@ -3,17 +3,186 @@
 // Copyright Tock Contributors 2022.
 
 //! Support for the 32-bit RISC-V architecture.
+//!
+//! Cargo features
+//! --------------
+//!
+//! - Default: RV32I. The trap handler and context switch save and restore all
+//!   32 integer registers.
+//! - `rv32e`: RV32E, for cores that only implement `x0`–`x15`. The trap
+//!   handler and context switch only touch those registers, and
+//!   [`syscall::Riscv32iStoredState`] only holds those.
+//! - `kernel_fault_state`: [`kernel_fault`] appends the RISC-V machine state
+//!   to its panic message. See [`PRINT_STATE_ON_KERNEL_FAULT`].
 
 #![crate_name = "rv32i"]
 #![crate_type = "rlib"]
+#![feature(asm_const)]
 #![feature(naked_functions)]
 #![no_std]
 
-use core::fmt::Write;
+use core::fmt::{Arguments, Display, Formatter, Write};
 
 use kernel::utilities::registers::interfaces::{Readable, Writeable};
 
+/// Assembly saving the app registers RV32E does not have (`x16`–`x31`) to
+/// the stored state pointed to by `s0`.
+#[cfg(all(target_arch = "riscv32", target_os = "none", not(feature = "rv32e")))]
+macro_rules! save_upper_app_registers {
+    () => {
+        "
+            sw   x16, 15*4(s0) // a6
+            sw   x17, 16*4(s0) // a7
+            sw   x18, 17*4(s0) // s2
+            sw   x19, 18*4(s0) // s3
+            sw   x20, 19*4(s0) // s4
+            sw   x21, 20*4(s0) // s5
+            sw   x22, 21*4(s0) // s6
+            sw   x23, 22*4(s0) // s7
+            sw   x24, 23*4(s0) // s8
+            sw   x25, 24*4(s0) // s9
+            sw   x26, 25*4(s0) // s10
+            sw   x27, 26*4(s0) // s11
+            sw   x28, 27*4(s0) // t3
+            sw   x29, 28*4(s0) // t4
+            sw   x30, 29*4(s0) // t5
+            sw   x31, 30*4(s0) // t6
+        "
+    };
+}
+#[cfg(all(target_arch = "riscv32", target_os = "none", feature = "rv32e"))]
+macro_rules! save_upper_app_registers {
+    () => {
+        ""
+    };
+}
+
+/// Assembly restoring the app registers RV32E does not have (`x16`–`x31`)
+/// from the stored state pointed to by `t0`.
+#[cfg(all(target_arch = "riscv32", target_os = "none", not(feature = "rv32e")))]
+macro_rules! restore_upper_app_registers {
+    () => {
+        "
+          lw   x16, 15*4(t0) // a6
+          lw   x17, 16*4(t0) // a7
+          lw   x18, 17*4(t0) // s2
+          lw   x19, 18*4(t0) // s3
+          lw   x20, 19*4(t0) // s4
+          lw   x21, 20*4(t0) // s5
+          lw   x22, 21*4(t0) // s6
+          lw   x23, 22*4(t0) // s7
+          lw   x24, 23*4(t0) // s8
+          lw   x25, 24*4(t0) // s9
+          lw   x26, 25*4(t0) // s10
+          lw   x27, 26*4(t0) // s11
+          lw   x28, 27*4(t0) // t3
+          lw   x29, 28*4(t0) // t4
+          lw   x30, 29*4(t0) // t5
+          lw   x31, 30*4(t0) // t6
+        "
+    };
+}
+#[cfg(all(target_arch = "riscv32", target_os = "none", feature = "rv32e"))]
+macro_rules! restore_upper_app_registers {
+    () => {
+        ""
+    };
+}
+
+/// Assembly saving the registers `_start_trap_rust_from_kernel` may clobber
+/// to the `KERNEL_TRAP_FRAME` bytes at `sp`.
+#[cfg(all(target_arch = "riscv32", target_os = "none", not(feature = "rv32e")))]
+macro_rules! save_kernel_caller_saved {
+    () => {
+        "
+            sw   ra, 0*4(sp)
+            sw   t0, 1*4(sp)
+            sw   t1, 2*4(sp)
+            sw   t2, 3*4(sp)
+            sw   t3, 4*4(sp)
+            sw   t4, 5*4(sp)
+            sw   t5, 6*4(sp)
+            sw   t6, 7*4(sp)
+            sw   a0, 8*4(sp)
+            sw   a1, 9*4(sp)
+            sw   a2, 10*4(sp)
+            sw   a3, 11*4(sp)
+            sw   a4, 12*4(sp)
+            sw   a5, 13*4(sp)
+            sw   a6, 14*4(sp)
+            sw   a7, 15*4(sp)
+        "
+    };
+}
+#[cfg(all(target_arch = "riscv32", target_os = "none", feature = "rv32e"))]
+macro_rules! save_kernel_caller_saved {
+    () => {
+        "
+            sw   ra, 0*4(sp)
+            sw   t0, 1*4(sp)
+            sw   t1, 2*4(sp)
+            sw   t2, 3*4(sp)
+            sw   a0, 4*4(sp)
+            sw   a1, 5*4(sp)
+            sw   a2, 6*4(sp)
+            sw   a3, 7*4(sp)
+            sw   a4, 8*4(sp)
+            sw   a5, 9*4(sp)
+        "
+    };
+}
+
+/// Assembly restoring the registers saved by `save_kernel_caller_saved`.
+#[cfg(all(target_arch = "riscv32", target_os = "none", not(feature = "rv32e")))]
+macro_rules! restore_kernel_caller_saved {
+    () => {
+        "
+            lw   ra, 0*4(sp)
+            lw   t0, 1*4(sp)
+            lw   t1, 2*4(sp)
+            lw   t2, 3*4(sp)
+            lw   t3, 4*4(sp)
+            lw   t4, 5*4(sp)
+            lw   t5, 6*4(sp)
+            lw   t6, 7*4(sp)
+            lw   a0, 8*4(sp)
+            lw   a1, 9*4(sp)
+            lw   a2, 10*4(sp)
+            lw   a3, 11*4(sp)
+            lw   a4, 12*4(sp)
+            lw   a5, 13*4(sp)
+            lw   a6, 14*4(sp)
+            lw   a7, 15*4(sp)
+        "
+    };
+}
+#[cfg(all(target_arch = "riscv32", target_os = "none", feature = "rv32e"))]
+macro_rules! restore_kernel_caller_saved {
+    () => {
+        "
+            lw   ra, 0*4(sp)
+            lw   t0, 1*4(sp)
+            lw   t1, 2*4(sp)
+            lw   t2, 3*4(sp)
+            lw   a0, 4*4(sp)
+            lw   a1, 5*4(sp)
+            lw   a2, 6*4(sp)
+            lw   a3, 7*4(sp)
+            lw   a4, 8*4(sp)
+            lw   a5, 9*4(sp)
+        "
+    };
+}
+
+/// Size of the stack frame `_start_trap` uses for the caller-saved registers
+/// when trapping from the kernel.
+#[cfg(all(target_arch = "riscv32", target_os = "none", not(feature = "rv32e")))]
+const KERNEL_TRAP_FRAME: usize = 16 * 4;
+#[cfg(all(target_arch = "riscv32", target_os = "none", feature = "rv32e"))]
+const KERNEL_TRAP_FRAME: usize = 10 * 4;
+
 pub mod clic;
+pub mod counters;
 pub mod machine_timer;
 pub mod pmp;
 pub mod support;
@@ -261,8 +430,30 @@ pub extern "C" fn _start_trap() {
             // going to panic at this point, but for that to work we need a
             // valid stack to run the panic code. We do this by just starting
             // over with the kernel stack and placing the stack pointer at the
-            // top of the original stack.
+            // top of the original stack. This gives the panic handler the
+            // whole kernel stack while leaving the bottom of the stack, where
+            // the overflow happened, untouched. `_estack` is only 8-byte
+            // aligned, so round down to the 16 bytes the calling convention
+            // requires.
             la sp, {estack}                     // sp = _estack
+            andi sp, sp, -16                    // sp = sp & ~0xF
+
+            // Restore t0 and clear mscratch, as below.
+            csrrw t0, 0x340, zero // t0=mscratch, mscratch=0
+
+            // Save the caller saved registers so they show up in a debugger.
+            addi sp, sp, -{kernel_frame}
+            ",
+            save_kernel_caller_saved!(),
+            "
+
+            // Tell `_start_trap_rust_from_kernel` that the kernel stack
+            // overflowed. It must not return in this case, as there is no
+            // valid stack to return to.
+            li   a0, 1
+            jal  ra, _start_trap_rust_from_kernel
+        101: // _from_kernel_stack_overflow_spin
+            j    101b
 
 
         100: // _from_kernel_continue
@@ -273,51 +464,27 @@ pub extern "C" fn _start_trap() {
 
             // Make room for the caller saved registers we need to restore after
             // running any trap handler code.
-            addi sp, sp, -16*4
+            addi sp, sp, -{kernel_frame}
 
             // Save all of the caller saved registers.
-            sw   ra, 0*4(sp)
-            sw   t0, 1*4(sp)
-            sw   t1, 2*4(sp)
-            sw   t2, 3*4(sp)
-            sw   t3, 4*4(sp)
-            sw   t4, 5*4(sp)
-            sw   t5, 6*4(sp)
-            sw   t6, 7*4(sp)
-            sw   a0, 8*4(sp)
-            sw   a1, 9*4(sp)
-            sw   a2, 10*4(sp)
-            sw   a3, 11*4(sp)
-            sw   a4, 12*4(sp)
-            sw   a5, 13*4(sp)
-            sw   a6, 14*4(sp)
-            sw   a7, 15*4(sp)
+            ",
+            save_kernel_caller_saved!(),
+            "
 
             // Jump to board-specific trap handler code. Likely this was an
             // interrupt and we want to disable a particular interrupt, but each
-            // board/chip can customize this as needed.
+            // board/chip can customize this as needed. The first argument is
+            // zero, as the kernel stack did not overflow.
+            li   a0, 0
             jal ra, _start_trap_rust_from_kernel
 
             // Restore the registers from the stack.
-            lw   ra, 0*4(sp)
-            lw   t0, 1*4(sp)
-            lw   t1, 2*4(sp)
-            lw   t2, 3*4(sp)
-            lw   t3, 4*4(sp)
-            lw   t4, 5*4(sp)
-            lw   t5, 6*4(sp)
-            lw   t6, 7*4(sp)
-            lw   a0, 8*4(sp)
-            lw   a1, 9*4(sp)
-            lw   a2, 10*4(sp)
-            lw   a3, 11*4(sp)
-            lw   a4, 12*4(sp)
-            lw   a5, 13*4(sp)
-            lw   a6, 14*4(sp)
-            lw   a7, 15*4(sp)
+            ",
+            restore_kernel_caller_saved!(),
+            "
 
             // Reset the stack pointer.
-            addi sp, sp, 16*4
+            addi sp, sp, {kernel_frame}
 
             // mret returns from the trap handler. The PC is set to what is in
             // mepc and execution proceeds from there. Since we did not modify
@@ -363,22 +530,9 @@ pub extern "C" fn _start_trap() {
             sw   x13, 12*4(s0) // a3
             sw   x14, 13*4(s0) // a4
             sw   x15, 14*4(s0) // a5
-            sw   x16, 15*4(s0) // a6
-            sw   x17, 16*4(s0) // a7
-            sw   x18, 17*4(s0) // s2
-            sw   x19, 18*4(s0) // s3
-            sw   x20, 19*4(s0) // s4
-            sw   x21, 20*4(s0) // s5
-            sw   x22, 21*4(s0) // s6
-            sw   x23, 22*4(s0) // s7
-            sw   x24, 23*4(s0) // s8
-            sw   x25, 24*4(s0) // s9
-            sw   x26, 25*4(s0) // s10
-            sw   x27, 26*4(s0) // s11
-            sw   x28, 27*4(s0) // t3
-            sw   x29, 28*4(s0) // t4
-            sw   x30, 29*4(s0) // t5
-            sw   x31, 30*4(s0) // t6
+            ",
+            save_upper_app_registers!(),
+            "
             // Now retrieve the original value of s0 and save that as well.
             lw   t0,  0*4(sp)
             sw   t0,  7*4(s0)  // s0,fp
@@ -392,13 +546,13 @@ pub extern "C" fn _start_trap() {
             csrr t0, 0x340    // CSR=0x340=mscratch
             sw   t0, 1*4(s0)  // Save the app sp to the stored state struct
             csrr t0, 0x341    // CSR=0x341=mepc
-            sw   t0, 31*4(s0) // Save the PC to the stored state struct
+            sw   t0, {pc}(s0) // Save the PC to the stored state struct
             csrr t0, 0x343    // CSR=0x343=mtval
-            sw   t0, 33*4(s0) // Save mtval to the stored state struct
+            sw   t0, {mtval}(s0) // Save mtval to the stored state struct
 
             // Save mcause last, as we depend on it being loaded in t0 below
             csrr t0, 0x342    // CSR=0x342=mcause
-            sw   t0, 32*4(s0) // Save mcause to the stored state struct, leave in t0
+            sw   t0, {mcause}(s0) // Save mcause to the stored state struct, leave in t0
 
             // Now we need to check if this was an interrupt, and if it was,
             // then we need to disable the interrupt before returning from this
@@ -433,6 +587,10 @@ pub extern "C" fn _start_trap() {
         ",
             estack = sym _estack,
             sstack = sym _sstack,
+            kernel_frame = const KERNEL_TRAP_FRAME,
+            pc = const syscall::PC_OFFSET,
+            mcause = const syscall::MCAUSE_OFFSET,
+            mtval = const syscall::MTVAL_OFFSET,
             options(noreturn)
         );
     }
@@ -582,14 +740,30 @@ pub unsafe fn print_riscv_state(writer: &mut dyn Write) {
          \r\nSystem register dump:\
          \r\n mepc:    {:#010X}    mstatus:     {:#010X}\
          \r\n mcycle:  {:#010X}    minstret:    {:#010X}\
-         \r\n mtvec:   {:#010X}",
+         \r\n mtvec:   {:#010X}    mscratch:    {:#010X}\
+         \r\n mcounteren: {:#010X}",
         csr::CSR.mtval.get(),
         csr::CSR.mepc.get(),
         csr::CSR.mstatus.get(),
         csr::CSR.mcycle.get(),
         csr::CSR.minstret.get(),
-        csr::CSR.mtvec.get()
+        csr::CSR.mtvec.get(),
+        csr::CSR.mscratch.get(),
+        csr::CSR.mcounteren.get()
     ));
+    // The delegation registers only exist with S-mode, and reading them on a
+    // hart without it raises an illegal instruction exception.
+    if csr::CSR.misa.is_set(csr::misa::misa::s) {
+        let _ = writer.write_fmt(format_args!(
+            "\r\n medeleg: {:#010X}    mideleg:     {:#010X}",
+            csr::CSR.medeleg.get(),
+            csr::CSR.mideleg.get()
+        ));
+    } else {
+        let _ = writer.write_fmt(format_args!(
+            "\r\n medeleg/mideleg: not read, misa does not report S-mode"
+        ));
+    }
     let mstatus = csr::CSR.mstatus.extract();
     let uie = mstatus.is_set(csr::mstatus::mstatus::uie);
     let sie = mstatus.is_set(csr::mstatus::mstatus::sie);
@@ -665,3 +839,121 @@ pub unsafe fn print_riscv_state(writer: &mut dyn Write) {
         p_mext
     ));
 }
+
+/// Report a kernel stack overflow detected by `_start_trap`.
+///
+/// `_start_trap` passes a non-zero `stack_overflow` argument to
+/// `_start_trap_rust_from_kernel` when the kernel stack pointer was at or
+/// below `_sstack` on trap entry, after moving the stack pointer back to the
+/// top of the kernel stack. Chips should call this first thing in their
+/// `_start_trap_rust_from_kernel` handler. It panics if `stack_overflow` is
+/// non-zero, leaving the board's panic handler to print the machine state, and
+/// returns otherwise.
+pub fn check_kernel_stack_overflow(stack_overflow: usize) {
+    if stack_overflow != 0 {
+        panic!("kernel stack overflow detected");
+    }
+}
+
+/// Whether [`kernel_fault`] dumps the machine state as part of its panic
+/// message. Enabled by the `kernel_fault_state` feature.
+///
+/// The dump is written while the panic handler prints the panic message,
+/// before anything else it prints, so the trap CSRs still describe the
+/// fault. Panic handlers that also print `Chip::print_state` show the state a
+/// second time, so this is meant for boards whose panic handler does not.
+pub const PRINT_STATE_ON_KERNEL_FAULT: bool = cfg!(feature = "kernel_fault_state");
+
+/// Panic on an unrecoverable trap taken while the kernel was running.
+///
+/// Chips should call this from their `_start_trap_rust_from_kernel` handler
+/// instead of panicking directly, so kernel faults are reported the same way
+/// on every RISC-V chip. If [`PRINT_STATE_ON_KERNEL_FAULT`] is set, the panic
+/// message is followed by [`print_riscv_state`].
+pub fn kernel_fault(args: Arguments) -> ! {
+    let print_state: Option<unsafe fn(&mut dyn Write)> =
+        PRINT_STATE_ON_KERNEL_FAULT.then_some(print_riscv_state);
+    panic!("{}", KernelFault { args, print_state })
+}
+
+/// The panic message of a kernel fault: the fault description, followed by
+/// the machine state if `print_state` is set.
+struct KernelFault<'a> {
+    args: Arguments<'a>,
+    print_state: Option<unsafe fn(&mut dyn Write)>,
+}
+
+impl Display for KernelFault<'_> {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        f.write_fmt(format_args!("Kernel fault: {}", self.args))?;
+        if let Some(print_state) = self.print_state {
+            unsafe { print_state(f) };
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::string::String;
+
+    unsafe fn mock_state(writer: &mut dyn Write) {
+        let _ = writer.write_str("\r\n[state]");
+    }
+
+    /// Panic like `kernel_fault` with `print_state` and return the message.
+    fn fault_message(print_state: Option<unsafe fn(&mut dyn Write)>) -> String {
+        let payload = std::panic::catch_unwind(|| {
+            panic!(
+                "{}",
+                KernelFault {
+                    args: format_args!("bad trap {}", 7),
+                    print_state,
+                }
+            )
+        })
+        .unwrap_err();
+        *payload.downcast::<String>().unwrap()
+    }
+
+    #[test]
+    fn kernel_fault_dumps_state_after_message() {
+        assert_eq!(
+            fault_message(Some(mock_state)),
+            "Kernel fault: bad trap 7\r\n[state]"
+        );
+    }
+
+    #[test]
+    fn kernel_fault_without_state() {
+        assert_eq!(fault_message(None), "Kernel fault: bad trap 7");
+    }
+
+    #[test]
+    fn kernel_fault_is_off_by_default() {
+        // With the feature, the state dump would read the CSRs, which is
+        // unimplemented on the host.
+        if PRINT_STATE_ON_KERNEL_FAULT {
+            return;
+        }
+        let payload =
+            std::panic::catch_unwind(|| kernel_fault(format_args!("bad trap {}", 7))).unwrap_err();
+        assert_eq!(
+            payload.downcast_ref::<String>().map(String::as_str),
+            Some("Kernel fault: bad trap 7")
+        );
+    }
+
+    #[test]
+    fn kernel_stack_overflow_panics() {
+        check_kernel_stack_overflow(0);
+        let payload = std::panic::catch_unwind(|| check_kernel_stack_overflow(1)).unwrap_err();
+        assert_eq!(
+            payload.downcast_ref::<&str>(),
+            Some(&"kernel stack overflow detected")
+        );
+    }
+}
@@ -10,6 +10,7 @@ use core::{cmp, fmt};
 use kernel::platform::mpu;
 use kernel::utilities::cells::OptionalCell;
 use kernel::utilities::registers::{register_bitfields, LocalRegisterCopy};
+use kernel::ErrorCode;
 
 use crate::csr;
 
@@ -873,6 +874,96 @@ impl<const MAX_REGIONS: usize, P: TORUserPMP<MAX_REGIONS> + 'static> kernel::pla
     }
 }
 
+/// Maximum number of PMP entries a hardware implementation can provide.
+pub const MAX_PMP_ENTRIES: usize = 64;
+
+/// Build the `pmpcfg` octet for an unlocked NAPOT region with permissions
+/// `perms`.
+pub fn napot_pmpcfg(perms: mpu::Permissions) -> u8 {
+    let mut cfg = TORUserPMPCFG::from(perms).get_reg();
+    cfg.modify(pmpcfg_octet::a::NAPOT);
+    cfg.get()
+}
+
+/// Encode a `base` address and `size` into a NAPOT `pmpaddrX` value.
+///
+/// Returns `INVAL` unless `size` is a power of two of at least 8 bytes and
+/// `base` is aligned to `size`.
+pub fn napot_pmpaddr(base: usize, size: usize) -> Result<usize, ErrorCode> {
+    NAPOTRegionSpec::new(base as *const u8, size)
+        .map(|region| region.napot_addr())
+        .ok_or(ErrorCode::INVAL)
+}
+
+/// Replace the octet belonging to PMP entry `region` in the value of its
+/// `pmpcfgX` CSR.
+fn with_pmpcfg_octet(pmpcfg: usize, region: usize, octet: u8) -> usize {
+    let shift = (region % 4) * 8;
+    (pmpcfg & !(0xFF << shift)) | ((octet as usize) << shift)
+}
+
+/// Extract the octet belonging to PMP entry `region` from the value of its
+/// `pmpcfgX` CSR.
+fn pmpcfg_octet_of(pmpcfg: usize, region: usize) -> LocalRegisterCopy<u8, pmpcfg_octet::Register> {
+    LocalRegisterCopy::new(pmpcfg.overflowing_shr(((region % 4) * 8) as u32).0 as u8)
+}
+
+/// Configure PMP entry `region` to cover `size` bytes from `base` in NAPOT
+/// mode, with permissions `perms` for user mode.
+///
+/// Returns `INVAL` if `region` is not a valid PMP entry or `base` and
+/// `size` do not describe a NAPOT region (see [`NAPOTRegionSpec`]), and
+/// `FAIL` if the entry is locked. The entry is switched off while its
+/// address is being changed.
+///
+/// # Safety
+///
+/// This writes the PMP CSRs directly. The caller must ensure `region` is
+/// not managed by a PMP implementation such as [`PMPUserMPU`] or
+/// [`kernel_protection::KernelProtectionPMP`], which would otherwise
+/// silently lose or overwrite this configuration.
+pub unsafe fn configure_napot(
+    region: usize,
+    base: usize,
+    size: usize,
+    perms: mpu::Permissions,
+) -> Result<(), ErrorCode> {
+    if region >= MAX_PMP_ENTRIES {
+        return Err(ErrorCode::INVAL);
+    }
+    let pmpaddr = napot_pmpaddr(base, size)?;
+
+    let pmpcfg = csr::CSR.pmpconfig_get(region / 4);
+    if pmpcfg_octet_of(pmpcfg, region).is_set(pmpcfg_octet::l) {
+        return Err(ErrorCode::FAIL);
+    }
+    let pmpcfg = with_pmpcfg_octet(pmpcfg, region, 0);
+    csr::CSR.pmpconfig_set(region / 4, pmpcfg);
+    csr::CSR.pmpaddr_set(region, pmpaddr);
+    csr::CSR.pmpconfig_set(
+        region / 4,
+        with_pmpcfg_octet(pmpcfg, region, napot_pmpcfg(perms)),
+    );
+    Ok(())
+}
+
+/// Switch off PMP entry `region`. Locked entries and entries beyond
+/// [`MAX_PMP_ENTRIES`] are left alone.
+///
+/// # Safety
+///
+/// As for [`configure_napot`], `region` must not be managed by another PMP
+/// implementation.
+pub unsafe fn disable_region(region: usize) {
+    if region >= MAX_PMP_ENTRIES {
+        return;
+    }
+    let pmpcfg = csr::CSR.pmpconfig_get(region / 4);
+    if !pmpcfg_octet_of(pmpcfg, region).is_set(pmpcfg_octet::l) {
+        csr::CSR.pmpconfig_set(region / 4, with_pmpcfg_octet(pmpcfg, region, 0));
+    }
+}
+
 pub mod test {
     use super::{TORUserPMP, TORUserPMPCFG};
 
@@ -1117,6 +1208,48 @@ pub mod test {
             )
             .is_none());
     }
+
+    #[test]
+    fn test_napot_pmpaddr_encoding() {
+        use super::napot_pmpaddr;
+        use kernel::ErrorCode;
+
+        // The trailing ones below the address encode the size: a 2^n byte
+        // region has n - 3 of them.
+        assert_eq!(napot_pmpaddr(0x8000_0000, 8), Ok(0x2000_0000));
+        assert_eq!(napot_pmpaddr(0x8000_0000, 16), Ok(0x2000_0001));
+        assert_eq!(napot_pmpaddr(0x8000_0000, 0x1000), Ok(0x2000_01FF));
+        assert_eq!(napot_pmpaddr(0x2000_0000, 0x2000_0000), Ok(0x0BFF_FFFF));
+
+        // Too small, not a power of two, or misaligned.
+        assert_eq!(napot_pmpaddr(0x8000_0000, 4), Err(ErrorCode::INVAL));
+        assert_eq!(napot_pmpaddr(0x8000_0000, 0x3000), Err(ErrorCode::INVAL));
+        assert_eq!(napot_pmpaddr(0x8000_0800, 0x1000), Err(ErrorCode::INVAL));
+    }
+
+    #[test]
+    fn test_napot_pmpcfg_encoding() {
+        use super::napot_pmpcfg;
+        use kernel::platform::mpu::Permissions;
+
+        // A = NAPOT (0b11 << 3), L clear.
+        assert_eq!(napot_pmpcfg(Permissions::ReadWriteExecute), 0x1F);
+        assert_eq!(napot_pmpcfg(Permissions::ReadWriteOnly), 0x1B);
+        assert_eq!(napot_pmpcfg(Permissions::ReadExecuteOnly), 0x1D);
+        assert_eq!(napot_pmpcfg(Permissions::ReadOnly), 0x19);
+        assert_eq!(napot_pmpcfg(Permissions::ExecuteOnly), 0x1C);
+    }
+
+    #[test]
+    fn test_pmpcfg_octet_placement() {
+        use super::{pmpcfg_octet_of, with_pmpcfg_octet};
+
+        let cfg = with_pmpcfg_octet(0x4433_2211, 6, 0x1F);
+        assert_eq!(cfg, 0x441F_2211);
+        assert_eq!(pmpcfg_octet_of(cfg, 6).get(), 0x1F);
+        assert_eq!(pmpcfg_octet_of(cfg, 3).get(), 0x44);
+        assert_eq!(with_pmpcfg_octet(cfg, 3, 0), 0x001F_2211);
+    }
 }
 
 pub mod simple {
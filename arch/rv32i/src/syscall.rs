@@ -16,11 +16,27 @@ use kernel::syscall::ContextSwitchReason;
 
 /// This holds all of the state that the kernel must keep for the process when
 /// the process is not executing.
+///
+/// The trap handler (`_start_trap`) and `switch_to_process()` access this
+/// struct from assembly by fixed word offsets, so the layout must not change
+/// without updating both. The canonical offsets are:
+///
+/// | Word    | Byte offset | Contents                                 |
+/// |---------|-------------|------------------------------------------|
+/// | 0       | 0           | `x1` (ra)                                |
+/// | 1       | 4           | `x2` (sp)                                |
+/// | n - 1   | 4 * (n - 1) | `xn`, for n in 3..=31                    |
+/// | 31      | 124         | `pc` (saved from `mepc`)                 |
+/// | 32      | 128         | `mcause`                                 |
+/// | 33      | 132         | `mtval`                                  |
+///
+/// With the `rv32e` feature only `x1`–`x15` are stored, so `pc`, `mcause`
+/// and `mtval` follow at words 15, 16 and 17.
 #[derive(Default)]
 #[repr(C)]
 pub struct Riscv32iStoredState {
     /// Store all of the app registers.
-    regs: [u32; 31],
+    regs: [u32; NUM_REGS],
 
     /// This holds the PC value of the app when the exception/syscall/interrupt
     /// occurred. We also use this to set the PC that the app should start
@@ -37,6 +53,23 @@ pub struct Riscv32iStoredState {
     mtval: u32,
 }
 
+/// Number of app registers kept in `Riscv32iStoredState`: all but `x0`.
+#[cfg(not(feature = "rv32e"))]
+const NUM_REGS: usize = 31;
+#[cfg(feature = "rv32e")]
+const NUM_REGS: usize = 15;
+
+// Byte offsets of the fields following the registers, for the assembly.
+#[cfg(any(test, all(target_arch = "riscv32", target_os = "none")))]
+pub(crate) const PC_OFFSET: usize = NUM_REGS * 4;
+#[cfg(any(test, all(target_arch = "riscv32", target_os = "none")))]
+pub(crate) const MCAUSE_OFFSET: usize = (NUM_REGS + 1) * 4;
+#[cfg(any(test, all(target_arch = "riscv32", target_os = "none")))]
+pub(crate) const MTVAL_OFFSET: usize = (NUM_REGS + 2) * 4;
+
+// The assembly assumes the struct is exactly the registers plus three words.
+const _: () = assert!(size_of::<Riscv32iStoredState>() == (NUM_REGS + 3) * 4);
+
 // Named offsets into the stored state registers.  These needs to be kept in
 // sync with the register save logic in _start_trap() as well as the register
 // restore logic in switch_to_process() below.
@@ -48,6 +81,28 @@ const R_A2: usize = 11;
 const R_A3: usize = 12;
 const R_A4: usize = 13;
 
+impl Riscv32iStoredState {
+    /// The app's stack pointer (`x2`) as saved on the last trap.
+    pub fn app_stack_pointer(&self) -> usize {
+        self.regs[R_SP] as usize
+    }
+
+    /// The app's program counter: where it trapped, or where it resumes.
+    pub fn pc(&self) -> usize {
+        self.pc as usize
+    }
+
+    /// The `mcause` CSR captured on the last trap from the app.
+    pub fn mcause(&self) -> usize {
+        self.mcause as usize
+    }
+
+    /// The `mtval` CSR captured on the last trap from the app.
+    pub fn mtval(&self) -> usize {
+        self.mtval as usize
+    }
+}
+
 /// Values for encoding the stored state buffer in a binary slice.
 const VERSION: u32 = 1;
 const STORED_STATE_SIZE: u32 = size_of::<Riscv32iStoredState>() as u32;
@@ -61,7 +116,7 @@ const PC_IDX: usize = 3;
 const MCAUSE_IDX: usize = 4;
 const MTVAL_IDX: usize = 5;
 const REGS_IDX: usize = 6;
-const REGS_RANGE: Range<usize> = REGS_IDX..REGS_IDX + 31;
+const REGS_RANGE: Range<usize> = REGS_IDX..REGS_IDX + NUM_REGS;
 
 const U32_SZ: usize = size_of::<u32>();
 fn u32_byte_range(index: usize) -> Range<usize> {
@@ -93,7 +148,7 @@ impl core::convert::TryFrom<&[u8]> for Riscv32iStoredState {
             && u32_from_u8_slice(ss, TAG_IDX)? == u32::from_le_bytes(TAG)
         {
             let mut res = Riscv32iStoredState {
-                regs: [0; 31],
+                regs: [0; NUM_REGS],
                 pc: u32_from_u8_slice(ss, PC_IDX)?,
                 mcause: u32_from_u8_slice(ss, MCAUSE_IDX)?,
                 mtval: u32_from_u8_slice(ss, MTVAL_IDX)?,
@@ -235,17 +290,12 @@ impl kernel::syscall::UserspaceKernelBoundary for SysCall {
         state: &mut Riscv32iStoredState,
     ) -> (ContextSwitchReason, Option<*const u8>) {
         use core::arch::asm;
-        // We need to ensure that the compiler does not reorder
-        // kernel memory writes to after the userspace context switch
-        // to ensure we provide a consistent memory view of
-        // application-accessible buffers.
-        //
-        // The compiler will not be able to reorder memory accesses
-        // beyond this point, as the "nomem" option on the asm!-block
-        // is not set, hence the compiler has to assume the assembly
-        // will issue arbitrary memory accesses (acting as a compiler
-        // fence).
-        asm!("
+
+        // The assembly differs between RV32I and RV32E only in which
+        // registers it restores and, therefore, has to mark as clobbered.
+        macro_rules! switch_to_process_asm {
+            ($($clobbers:tt)*) => {
+                asm!("
           // Before switching to the app we need to save some kernel registers
           // to the kernel stack, specifically ones which we can't mark as
           // clobbered in the asm!() block. We then save the stack pointer in
@@ -326,7 +376,7 @@ impl kernel::syscall::UserspaceKernelBoundary for SysCall {
           // executing at. This has been saved in Riscv32iStoredState for us
           // (either when the app returned back to the kernel or in the
           // `set_process_function()` function).
-          lw   t0, 31*4(a0)   // Retrieve the PC from Riscv32iStoredState
+          lw   t0, {pc}(a0)   // Retrieve the PC from Riscv32iStoredState
           csrw 0x341, t0      // Set mepc CSR. This is the PC we want to go to.
 
           // Restore all of the app registers from what we saved. If this is the
@@ -349,22 +399,9 @@ impl kernel::syscall::UserspaceKernelBoundary for SysCall {
           lw   x13, 12*4(t0) // a3
           lw   x14, 13*4(t0) // a4
           lw   x15, 14*4(t0) // a5
-          lw   x16, 15*4(t0) // a6
-          lw   x17, 16*4(t0) // a7
-          lw   x18, 17*4(t0) // s2
-          lw   x19, 18*4(t0) // s3
-          lw   x20, 19*4(t0) // s4
-          lw   x21, 20*4(t0) // s5
-          lw   x22, 21*4(t0) // s6
-          lw   x23, 22*4(t0) // s7
-          lw   x24, 23*4(t0) // s8
-          lw   x25, 24*4(t0) // s9
-          lw   x26, 25*4(t0) // s10
-          lw   x27, 26*4(t0) // s11
-          lw   x28, 27*4(t0) // t3
-          lw   x29, 28*4(t0) // t4
-          lw   x30, 29*4(t0) // t5
-          lw   x31, 30*4(t0) // t6
+          ",
+          restore_upper_app_registers!(),
+          "
           lw   x5,  4*4(t0)  // t0. Do last since we overwrite our pointer.
 
           // Call mret to jump to where mepc points, switch to user mode, and
@@ -393,6 +430,7 @@ impl kernel::syscall::UserspaceKernelBoundary for SysCall {
 
           addi sp, sp, 8*4   // Reset kernel stack pointer
           ",
+          pc = const PC_OFFSET,
 
           // The register to put the state struct pointer in is not
           // particularly relevant, however we must avoid using t0
@@ -400,6 +438,23 @@ impl kernel::syscall::UserspaceKernelBoundary for SysCall {
           // (although stored and later restored) in the assembly
           in("a0") state as *mut Riscv32iStoredState,
 
+          $($clobbers)*
+                )
+            };
+        }
+
+        // We need to ensure that the compiler does not reorder
+        // kernel memory writes to after the userspace context switch
+        // to ensure we provide a consistent memory view of
+        // application-accessible buffers.
+        //
+        // The compiler will not be able to reorder memory accesses
+        // beyond this point, as the "nomem" option on the asm!-block
+        // is not set, hence the compiler has to assume the assembly
+        // will issue arbitrary memory accesses (acting as a compiler
+        // fence).
+        #[cfg(not(feature = "rv32e"))]
+        switch_to_process_asm!(
           // Clobber all registers which can be marked as clobbered, except
           // for `a0` / `x10`. By making it retain the value of `&mut state`,
           // which we need to stack manually anyway, we can avoid Rust/LLVM
@@ -410,6 +465,12 @@ impl kernel::syscall::UserspaceKernelBoundary for SysCall {
           out("x22") _, out("x23") _, out("x24") _, out("x25") _, out("x26") _,
           out("x27") _, out("x28") _, out("x29") _, out("x30") _, out("x31") _,
         );
+        // RV32E has no `x16`-`x31` to clobber.
+        #[cfg(feature = "rv32e")]
+        switch_to_process_asm!(
+          out("x1") _, out("x5") _, out("x6") _, out("x7") _, out("x11") _,
+          out("x12") _, out("x13") _, out("x14") _, out("x15") _,
+        );
 
         let ret = match mcause::Trap::from(state.mcause as usize) {
             mcause::Trap::Interrupt(_intr) => {
@@ -445,7 +506,7 @@ impl kernel::syscall::UserspaceKernelBoundary for SysCall {
                 }
             }
         };
-        let new_stack_pointer = state.regs[R_SP];
+        let new_stack_pointer = state.app_stack_pointer();
         (ret, Some(new_stack_pointer as *const u8))
     }
 
@@ -456,68 +517,36 @@ impl kernel::syscall::UserspaceKernelBoundary for SysCall {
         state: &Riscv32iStoredState,
         writer: &mut dyn Write,
     ) {
+        // Print the registers in two columns. x0 is hardwired to zero and not
+        // stored.
+        let reg = |n: usize| match n {
+            0 => Some(0),
+            n => state.regs.get(n - 1).copied(),
+        };
+        let rows = NUM_REGS.div_ceil(2);
+        for n in 0..rows {
+            let _ = writer.write_fmt(format_args!(
+                "\r\n R{:<2}: {:#010X}",
+                n,
+                reg(n).unwrap_or(0)
+            ));
+            if let Some(value) = reg(n + rows) {
+                let _ = writer.write_fmt(format_args!("    R{:<2}: {:#010X}", n + rows, value));
+            }
+        }
         let _ = writer.write_fmt(format_args!(
-            "\
-             \r\n R0 : {:#010X}    R16: {:#010X}\
-             \r\n R1 : {:#010X}    R17: {:#010X}\
-             \r\n R2 : {:#010X}    R18: {:#010X}\
-             \r\n R3 : {:#010X}    R19: {:#010X}\
-             \r\n R4 : {:#010X}    R20: {:#010X}\
-             \r\n R5 : {:#010X}    R21: {:#010X}\
-             \r\n R6 : {:#010X}    R22: {:#010X}\
-             \r\n R7 : {:#010X}    R23: {:#010X}\
-             \r\n R8 : {:#010X}    R24: {:#010X}\
-             \r\n R9 : {:#010X}    R25: {:#010X}\
-             \r\n R10: {:#010X}    R26: {:#010X}\
-             \r\n R11: {:#010X}    R27: {:#010X}\
-             \r\n R12: {:#010X}    R28: {:#010X}\
-             \r\n R13: {:#010X}    R29: {:#010X}\
-             \r\n R14: {:#010X}    R30: {:#010X}\
-             \r\n R15: {:#010X}    R31: {:#010X}\
-             \r\n PC : {:#010X}\
+            "\r\n PC : {:#010X}\
              \r\n\
              \r\n mcause: {:#010X} (",
-            0,
-            state.regs[15],
-            state.regs[0],
-            state.regs[16],
-            state.regs[1],
-            state.regs[17],
-            state.regs[2],
-            state.regs[18],
-            state.regs[3],
-            state.regs[19],
-            state.regs[4],
-            state.regs[20],
-            state.regs[5],
-            state.regs[21],
-            state.regs[6],
-            state.regs[22],
-            state.regs[7],
-            state.regs[23],
-            state.regs[8],
-            state.regs[24],
-            state.regs[9],
-            state.regs[25],
-            state.regs[10],
-            state.regs[26],
-            state.regs[11],
-            state.regs[27],
-            state.regs[12],
-            state.regs[28],
-            state.regs[13],
-            state.regs[29],
-            state.regs[14],
-            state.regs[30],
-            state.pc,
-            state.mcause,
+            state.pc(),
+            state.mcause(),
         ));
-        crate::print_mcause(mcause::Trap::from(state.mcause as usize), writer);
+        crate::print_mcause(mcause::Trap::from(state.mcause()), writer);
         let _ = writer.write_fmt(format_args!(
             ")\
              \r\n mtval:  {:#010X}\
              \r\n\r\n",
-            state.mtval,
+            state.mtval(),
         ));
     }
 
@@ -544,3 +573,67 @@ impl kernel::syscall::UserspaceKernelBoundary for SysCall {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stored_state_accessors() {
+        let mut state = Riscv32iStoredState::default();
+        state.regs[R_SP] = 0x2000_4000;
+        state.pc = 0x4003_0010;
+        state.mcause = 8;
+        state.mtval = 0xDEAD_BEEF;
+
+        assert_eq!(state.app_stack_pointer(), 0x2000_4000);
+        assert_eq!(state.pc(), 0x4003_0010);
+        assert_eq!(state.mcause(), 8);
+        assert_eq!(state.mtval(), 0xDEAD_BEEF);
+    }
+
+    #[test]
+    fn print_context_lists_registers_in_two_columns() {
+        extern crate std;
+        use kernel::syscall::UserspaceKernelBoundary;
+
+        let mut state = Riscv32iStoredState::default();
+        for (i, reg) in state.regs.iter_mut().enumerate() {
+            *reg = i as u32 + 1;
+        }
+        let mut out = std::string::String::new();
+        unsafe {
+            SysCall::new().print_context(core::ptr::null(), core::ptr::null(), &state, &mut out);
+        }
+
+        let rows = NUM_REGS.div_ceil(2);
+        assert!(out.starts_with(&std::format!(
+            "\r\n R0 : 0x00000000    R{:<2}: {:#010X}\r\n R1 : 0x00000001",
+            rows,
+            rows
+        )));
+        assert!(out.contains(&std::format!("R{}: {:#010X}\r\n PC :", NUM_REGS, NUM_REGS)));
+    }
+
+    /// Byte offset of `field` within `state`.
+    fn offset_of<T>(state: &Riscv32iStoredState, field: &T) -> usize {
+        field as *const T as usize - state as *const Riscv32iStoredState as usize
+    }
+
+    #[test]
+    fn stored_state_offsets_match_trap_handler() {
+        let state = Riscv32iStoredState::default();
+
+        // `sw x1, 0*4(s0)` through `sw x31, 30*4(s0)` (`x15, 14*4(s0)` for
+        // RV32E).
+        for i in 0..NUM_REGS {
+            assert_eq!(offset_of(&state, &state.regs[i]), i * 4);
+        }
+        // `sw t0, {pc}(s0)` (mepc), `{mcause}(s0)` and `{mtval}(s0)`.
+        assert_eq!(offset_of(&state, &state.pc), PC_OFFSET);
+        assert_eq!(offset_of(&state, &state.mcause), MCAUSE_OFFSET);
+        assert_eq!(offset_of(&state, &state.mtval), MTVAL_OFFSET);
+        #[cfg(not(feature = "rv32e"))]
+        assert_eq!((PC_OFFSET, MCAUSE_OFFSET, MTVAL_OFFSET), (124, 128, 132));
+    }
+}
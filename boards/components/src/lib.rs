@@ -46,6 +46,7 @@ pub mod kv;
 pub mod l3gd20;
 pub mod led;
 pub mod led_matrix;
+pub mod life;
 pub mod lldb;
 pub mod lpm013m126;
 pub mod lps22hb;
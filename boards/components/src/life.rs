@@ -7,40 +7,55 @@
 //! Usage
 //! -----
 //! ```rust
-//! let life = components::life::LifeComponent::new().finalize(());
+//! let life = components::life::LifeComponent::new(
+//!     board_kernel,
+//!     capsules_core::life::DRIVER_NUM,
+//!     &nrf52840_peripherals.nrf52.rtc,
+//! )
+//! .finalize(components::life_component_static!(nrf52840::rtc::Rtc));
 //! ```
 
 use capsules_core::life::LifeDriver;
-use core::marker::PhantomData;
+use core::mem::MaybeUninit;
 
+use kernel::capabilities;
 use kernel::component::Component;
+use kernel::create_capability;
+use kernel::hil::time::Time;
 
 #[macro_export]
 macro_rules! life_component_static {
-    () => {{
-        let life = kernel::static_init!(LifeDriver, LifeDriver::new());
-        life
+    ($T:ty $(,)?) => {{
+        kernel::static_buf!(capsules_core::life::LifeDriver<'static, $T>)
     }};
 }
 
-pub struct LifeComponent {
-    _phantom: PhantomData<LifeDriver>,
+pub struct LifeComponent<T: 'static + Time> {
+    board_kernel: &'static kernel::Kernel,
+    driver_num: usize,
+    time: &'static T,
 }
 
-impl LifeComponent {
-    pub fn new() -> Self {
+impl<T: 'static + Time> LifeComponent<T> {
+    pub fn new(board_kernel: &'static kernel::Kernel, driver_num: usize, time: &'static T) -> Self {
         Self {
-            _phantom: PhantomData,
+            board_kernel,
+            driver_num,
+            time,
         }
     }
 }
 
-impl Component for LifeComponent {
-    type StaticInput = ();
-    type Output = &'static LifeDriver;
+impl<T: 'static + Time> Component for LifeComponent<T> {
+    type StaticInput = &'static mut MaybeUninit<LifeDriver<'static, T>>;
+    type Output = &'static LifeDriver<'static, T>;
 
-    fn finalize(self, _static_buffer: Self::StaticInput) -> Self::Output {
-        let life = unsafe { life_component_static!() };
-        life
+    fn finalize(self, static_buffer: Self::StaticInput) -> Self::Output {
+        let grant_cap = create_capability!(capabilities::MemoryAllocationCapability);
+
+        static_buffer.write(LifeDriver::new(
+            self.time,
+            self.board_kernel.create_grant(self.driver_num, &grant_cap),
+        ))
     }
 }
@@ -2,6 +2,8 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 // Copyright Tock Contributors 2022.
 
+use capsules_core::uart1::UartCapsule;
+use capsules_core::virtualizers::virtual_uart::UartDevice;
 use core::fmt::Write;
 use core::panic::PanicInfo;
 use cortexm4;
@@ -9,9 +11,9 @@ use kernel::debug;
 use kernel::debug::IoWrite;
 use kernel::hil::led;
 use kernel::hil::uart;
-use kernel::hil::uart::Configure;
+use kernel::hil::uart::{Configure, TransmitSync};
 use nrf52840::gpio::Pin;
-use nrf52840::uart::{Uarte, UARTE0_BASE, UARTE1_BASE};
+use nrf52840::uart::{Uarte, UARTE0_BASE};
 
 use crate::CHIP;
 use crate::PROCESSES;
@@ -24,6 +26,9 @@ enum Writer {
 
 static mut WRITER: Writer = Writer::WriterUart(false);
 
+/// The UARTE1 capsule, which announces a panic on its own UART.
+static mut UART1_CAPSULE: Option<&'static UartCapsule<'static, UartDevice<'static>>> = None;
+
 // Wait a fixed number of cycles to avoid missing characters over the RTT console
 fn wait() {
     for _ in 0..1000 {
@@ -31,6 +36,12 @@ fn wait() {
     }
 }
 
+/// Set the UARTE1 capsule that is told about a panic. Its mux must have a
+/// polled transmit path set with `MuxUart::set_transmit_sync`.
+pub unsafe fn set_uart1_capsule(capsule: &'static UartCapsule<'static, UartDevice<'static>>) {
+    UART1_CAPSULE = Some(capsule);
+}
+
 /// Set the RTT memory buffer used to output panic messages.
 pub unsafe fn set_rtt_memory(
     rtt_memory: &'static capsules_extra::segger_rtt::SeggerRttMemory<'static>,
@@ -49,9 +60,13 @@ impl IoWrite for Writer {
     fn write(&mut self, buf: &[u8]) -> usize {
         match self {
             Writer::WriterUart(ref mut initialized) => {
-                // Here, we create a second instance of the Uarte struct.
-                // This is okay because we only call this during a panic, and
-                // we will never actually process the interrupts
+                // Here, we create a second instance of the Uarte struct for
+                // UARTE0. Until a panic, UARTE0 is owned by the console
+                // `uart_mux` in `main()`. This instance only exists on the
+                // panic path, after the kernel has stopped driving the
+                // peripheral, and we will never actually process its
+                // interrupts. UARTE1 belongs to `uart1_mux` at all times and
+                // is never touched here.
                 let uart = Uarte::new(UARTE0_BASE);
                 if !*initialized {
                     *initialized = true;
@@ -64,10 +79,7 @@ impl IoWrite for Writer {
                     });
                 }
                 for &c in buf {
-                    unsafe {
-                        uart.send_byte(c);
-                    }
-                    while !uart.tx_ready() {}
+                    uart.transmit_byte_sync(c);
                 }
             }
             Writer::WriterRtt(rtt_memory) => {
@@ -102,6 +114,10 @@ pub unsafe fn panic_fmt(pi: &PanicInfo) -> ! {
     // The nRF52840DK LEDs (see back of board)
     let led_kernel_pin = &nrf52840::gpio::GPIOPin::new(Pin::P0_13);
     let led = &mut led::LedLow::new(led_kernel_pin);
+    // The main loop has stopped, so only the polled path can reach UARTE1.
+    if let Some(capsule) = UART1_CAPSULE {
+        capsule.transmit_sync(b"\r\nKernel panic, see console\r\n");
+    }
     let writer = &mut WRITER;
     debug::panic(
         &mut [led],
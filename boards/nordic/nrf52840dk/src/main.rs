@@ -74,7 +74,7 @@
 #![deny(missing_docs)]
 
 use capsules_core::console;
-use capsules_core::uart1::UartCapsule;
+use capsules_core::uart1::{RxMode, UartCapsule};
 use capsules_core::virtualizers::virtual_alarm::VirtualMuxAlarm;
 use capsules_core::virtualizers::virtual_uart::{MuxUart, UartDevice};
 use capsules_extra::net::ieee802154::MacAddress;
@@ -95,7 +95,6 @@ use kernel::scheduler::round_robin::RoundRobinSched;
 #[allow(unused_imports)]
 use kernel::{capabilities, create_capability, debug, debug_gpio, debug_verbose, static_init};
 use nrf52::peripheral_interrupts::UART0;
-use nrf52::uart::{Uarte, UARTE0_BASE, UARTE1_BASE};
 use nrf52840::gpio::Pin;
 use nrf52840::interrupt_service::Nrf52840DefaultPeripherals;
 use nrf52_components::{self, UartChannel, UartPins};
@@ -251,7 +250,7 @@ pub struct Platform {
     kv_driver: &'static KVDriver,
     scheduler: &'static RoundRobinSched<'static>,
     systick: cortexm4::systick::SysTick,
-    life: &'static capsules_core::life::LifeDriver,
+    life: &'static capsules_core::life::LifeDriver<'static, nrf52840::rtc::Rtc<'static>>,
 }
 
 impl SyscallDriverLookup for Platform {
@@ -496,13 +495,6 @@ pub unsafe fn main() {
         LedLow::new(&nrf52840_peripherals.gpio_port[LED4_PIN]),
     ));
 
-    // let life: &'static capsules_core::life::LifeDriver =
-    //     components::life::LifeComponent::new().finalize(());
-    let life = kernel::static_init!(
-        capsules_core::life::LifeDriver,
-        capsules_core::life::LifeDriver::new()
-    );
-
     //--------------------------------------------------------------------------
     // TIMER
     //--------------------------------------------------------------------------
@@ -518,6 +510,10 @@ pub unsafe fn main() {
     )
     .finalize(components::alarm_component_static!(nrf52840::rtc::Rtc));
 
+    let life =
+        components::life::LifeComponent::new(board_kernel, capsules_core::life::DRIVER_NUM, rtc)
+            .finalize(components::life_component_static!(nrf52840::rtc::Rtc));
+
     //--------------------------------------------------------------------------
     // UART & CONSOLE & DEBUG
     //--------------------------------------------------------------------------
@@ -551,6 +547,8 @@ pub unsafe fn main() {
 
     let uart1_mux = components::console::UartMuxComponent::new(uart1_channel, 115200)
         .finalize(components::uart_mux_component_static!());
+    // Let devices on `uart1_mux` poll UARTE1 directly from the panic handler.
+    uart1_mux.set_transmit_sync(&base_peripherals.uarte1);
 
     // Create the process console, an interactive terminal for managing
     // processes.
@@ -964,48 +962,36 @@ pub unsafe fn main() {
     let _ = platform.pconsole.start();
     base_peripherals.adc.calibrate();
 
+    // Second UART (UARTE1). `uart1_mux` has its own mux buffer and this
+    // device its own tx/rx buffers, so it runs alongside the console on
+    // UARTE0 without sharing any memory with it.
     let tx_buffer = static_init!([u8; 20], [0; 20]);
     let rx_buffer = static_init!([u8; 20], [0; 20]);
     let device: &mut UartDevice<'_> =
         static_init!(UartDevice<'static>, UartDevice::new(uart1_mux, true));
     device.setup();
-    let test = static_init!(UartCapsule, UartCapsule::new(device, tx_buffer, rx_buffer),);
+    let test = static_init!(
+        UartCapsule<'static, UartDevice<'static>>,
+        UartCapsule::new(device, tx_buffer, rx_buffer, RxMode::PongIncrement),
+    );
 
-    // receive
-    static mut numbers: [u8; 20] = [ 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20];
+    static NUMBERS: [u8; 20] = [
+        1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20,
+    ];
     debug!("setting receive client");
     device.set_receive_client(test);
     device.set_transmit_client(test);
-    test.receive();
-    test.start_transmission(&mut numbers);
-
-    //transmit
-    
-    // device.set_transmit_client(test);
-    // device.set_receive_client(test);
-    // test.start_transmission(&mut numbers);
-
-    //HARDCODE TRANSMIT
-    // static mut BUF:[u8; 3] = [1, 2, 3];
-    // static mut RBUF: [u8; 7] = [0; 7];
-
-    // let device = static_init!(UartDevice<'static>, UartDevice::new(uart1_mux, true,));
-    // device.setup();
-    // let uart = Uarte::new(UARTE1_BASE);
-    // for num in BUF {
-    //     unsafe {
-    //         uart.send_byte(num);
-    //         debug!("{}", num);
-    //     }
-    //     while !uart.tx_ready() {}
-    //     // debug!("{}", num);
-    // }
+    self::io::set_uart1_capsule(test);
+    if let Err(code) = test.receive() {
+        debug!("uart1: receive failed: {:?}", code);
+    }
+    if let Err(code) = test.start_transmission(&NUMBERS) {
+        debug!("uart1: transmit failed: {:?}", code);
+    }
 
     // test::virtual_uart_nrf_test::run_virtual_uart_receive(uart1_mux);
     // test::virtual_uart_nrf_test::run_virtual_uart_transmit(uart1_mux);
-
-    // debug!("tx_buffer: {}", uart.tx_ready());
-    // how to access rx buffer?????????
+    // test::virtual_uart_nrf_test::run_virtual_uart_pingpong(uart1_mux);
 
     // test::aes_test::run_aes128_ctr(&base_peripherals.ecb);
     // test::aes_test::run_aes128_cbc(&base_peripherals.ecb);
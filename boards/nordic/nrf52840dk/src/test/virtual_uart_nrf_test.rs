@@ -47,15 +47,29 @@
 //! 61
 //! 61
 //! ```
+//!
+//! `run_virtual_uart_pingpong(uart1_mux)` instead transmits a fixed pattern
+//! on one virtual device and checks that another virtual device on the same
+//! mux receives exactly those bytes. It assumes the UART is looped back: on
+//! this board, `uart1_mux` uses P1.06 (TX) and P1.08 (RX), so jumper those
+//! two pins, and P1.05 (RTS) to P1.07 (CTS) for flow control. It prints either
+//! `Virtual uart pingpong: pass` or `Virtual uart pingpong: fail` with the
+//! received bytes.
 
 // use core::{result, error};
 
 use capsules_core::test::virtual_uart::{TestVirtualUartReceive, TestVirtualUartTransmit};
 use capsules_core::virtualizers::virtual_uart::{MuxUart, UartDevice};
 use kernel::debug;
-use kernel::hil::uart::{Receive, ReceiveClient, Error};
+use kernel::hil::uart;
 use kernel::hil::uart::Transmit;
+use kernel::hil::uart::{Error, Receive};
 use kernel::static_init;
+use kernel::utilities::cells::TakeCell;
+use kernel::ErrorCode;
+
+/// Bytes sent by `run_virtual_uart_pingpong`.
+const PINGPONG_PATTERN: [u8; 8] = [0x50, 0x49, 0x4e, 0x47, 0x00, 0x7f, 0x80, 0xff];
 
 pub unsafe fn run_virtual_uart_transmit(mux: &'static MuxUart<'static>) {
     debug!("Starting virtual writes.");
@@ -73,13 +87,109 @@ pub unsafe fn run_virtual_uart_receive(mux: &'static MuxUart<'static>) {
     large.run();
 }
 
+pub unsafe fn run_virtual_uart_pingpong(mux: &'static MuxUart<'static>) {
+    debug!("Starting virtual uart pingpong.");
+    let test = static_init_test_pingpong(mux);
+    test.run();
+}
+
+/// Sends `PINGPONG_PATTERN` on one virtual device and compares what a second
+/// virtual device receives against it.
+struct TestVirtualUartPingPong {
+    tx_device: &'static UartDevice<'static>,
+    rx_device: &'static UartDevice<'static>,
+    tx_buffer: TakeCell<'static, [u8]>,
+    rx_buffer: TakeCell<'static, [u8]>,
+}
+
+impl TestVirtualUartPingPong {
+    fn run(&self) {
+        // Arm the receive first so no looped back byte is missed.
+        let rx_buffer = self.rx_buffer.take().unwrap();
+        self.rx_device
+            .receive_buffer(rx_buffer, PINGPONG_PATTERN.len())
+            .expect("Calling receive_buffer() in virtual_uart pingpong failed");
+
+        let tx_buffer = self.tx_buffer.take().unwrap();
+        tx_buffer[..PINGPONG_PATTERN.len()].copy_from_slice(&PINGPONG_PATTERN);
+        if let Err((ecode, tx_buffer)) = self
+            .tx_device
+            .transmit_buffer(tx_buffer, PINGPONG_PATTERN.len())
+        {
+            debug!("Virtual uart pingpong: fail, transmit error {:?}", ecode);
+            self.tx_buffer.replace(tx_buffer);
+            let _ = self.rx_device.receive_abort();
+        }
+    }
+}
+
+impl uart::TransmitClient for TestVirtualUartPingPong {
+    fn transmitted_buffer(
+        &self,
+        tx_buffer: &'static mut [u8],
+        _tx_len: usize,
+        rcode: Result<(), ErrorCode>,
+    ) {
+        if let Err(ecode) = rcode {
+            debug!("Virtual uart pingpong: fail, transmit error {:?}", ecode);
+        }
+        self.tx_buffer.replace(tx_buffer);
+    }
+}
+
+impl uart::ReceiveClient for TestVirtualUartPingPong {
+    fn received_buffer(
+        &self,
+        rx_buffer: &'static mut [u8],
+        rx_len: usize,
+        rcode: Result<(), ErrorCode>,
+        _error: Error,
+    ) {
+        if rcode.is_ok() && rx_buffer[..rx_len] == PINGPONG_PATTERN {
+            debug!("Virtual uart pingpong: pass");
+        } else {
+            debug!(
+                "Virtual uart pingpong: fail, received {:02x?} ({:?})",
+                &rx_buffer[..rx_len],
+                rcode
+            );
+        }
+        self.rx_buffer.replace(rx_buffer);
+    }
+}
+
+unsafe fn static_init_device(mux: &'static MuxUart<'static>) -> &'static UartDevice<'static> {
+    let device = static_init!(UartDevice<'static>, UartDevice::new(mux, true));
+    device.setup();
+    device
+}
+
+unsafe fn static_init_test_pingpong(
+    mux: &'static MuxUart<'static>,
+) -> &'static TestVirtualUartPingPong {
+    static mut TX_BUFFER: [u8; PINGPONG_PATTERN.len()] = [0; PINGPONG_PATTERN.len()];
+    static mut RX_BUFFER: [u8; PINGPONG_PATTERN.len()] = [0; PINGPONG_PATTERN.len()];
+    let tx_device = static_init_device(mux);
+    let rx_device = static_init_device(mux);
+    let test = static_init!(
+        TestVirtualUartPingPong,
+        TestVirtualUartPingPong {
+            tx_device,
+            rx_device,
+            tx_buffer: TakeCell::new(&mut TX_BUFFER),
+            rx_buffer: TakeCell::new(&mut RX_BUFFER),
+        }
+    );
+    tx_device.set_transmit_client(test);
+    rx_device.set_receive_client(test);
+    test
+}
 
 unsafe fn static_init_test_receive_small(
     mux: &'static MuxUart<'static>,
 ) -> &'static TestVirtualUartReceive {
     static mut SMALL: [u8; 3] = [0; 3];
-    let device = static_init!(UartDevice<'static>, UartDevice::new(mux, true));
-    device.setup();
+    let device = static_init_device(mux);
     let test = static_init!(
         TestVirtualUartReceive,
         TestVirtualUartReceive::new(device, &mut SMALL)
@@ -92,8 +202,7 @@ unsafe fn static_init_test_receive_large(
     mux: &'static MuxUart<'static>,
 ) -> &'static TestVirtualUartReceive {
     static mut BUFFER: [u8; 7] = [0; 7];
-    let device = static_init!(UartDevice<'static>, UartDevice::new(mux, true));
-    device.setup();
+    let device = static_init_device(mux);
     let test = static_init!(
         TestVirtualUartReceive,
         TestVirtualUartReceive::new(device, &mut BUFFER)
@@ -106,8 +215,7 @@ unsafe fn static_init_test_transmit_small(
     mux: &'static MuxUart<'static>,
 ) -> &'static TestVirtualUartTransmit {
     static mut SMALL: [u8; 1] = [42; 1];
-    let device = static_init!(UartDevice<'static>, UartDevice::new(mux, true));
-    device.setup();
+    let device = static_init_device(mux);
     let test = static_init!(
         TestVirtualUartTransmit,
         TestVirtualUartTransmit::new(device, &mut SMALL)
@@ -120,8 +228,7 @@ unsafe fn static_init_test_transmit_large(
     mux: &'static MuxUart<'static>,
 ) -> &'static TestVirtualUartTransmit {
     static mut BUFFER: [u8; 7] = [100; 7];
-    let device = static_init!(UartDevice<'static>, UartDevice::new(mux, true));
-    device.setup();
+    let device = static_init_device(mux);
     let test = static_init!(
         TestVirtualUartTransmit,
         TestVirtualUartTransmit::new(device, &mut BUFFER)
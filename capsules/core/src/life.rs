@@ -30,15 +30,51 @@
 //!   - `data`: Unused.
 //!   - Return: The meaning of life (42) as a `u32`.
 //! - `1`: Check if the provided data is the meaning of life.
-//!   - `data`: The value to check against the meaning of life (42).
-//!   - Return: `Ok(())` if the data matches 42; otherwise, returns `INVAL` error code.
+//!   - `data`: The value to check against the calling process's meaning of
+//!     life, 42 unless changed with command `2`.
+//!   - Return: `Ok(())` if the data matches; otherwise, returns `INVAL` error
+//!     code. On a match the upcall subscribed with `subscribe_num` `0` is
+//!     also scheduled.
+//! - `2`: Set the meaning of life for the calling process.
+//!   - `data`: The new value checked by command `1`.
+//!   - Return: `Ok(())`.
+//! - `3`: Retrieve how many commands the calling process has issued.
+//!   - `data`: Unused.
+//!   - Return: The count as a `u32`, including this command.
+//! - `15`: Retrieve the rolling CRC of every command received so far.
+//!   - `data`: Unused.
+//!   - Return: The CRC-32 as a `u32`. Each command other than `15` folds its
+//!     `command_num`, `data` and second argument (in that order, each as
+//!     four little-endian bytes) into the CRC before it is handled. A host
+//!     that knows which commands it sent can recompute the value to check
+//!     that none were dropped or reordered on the way.
+//! - `16`: Retrieve one bucket of the command latency histogram.
+//!   - `data`: The bucket index, `0` to `LATENCY_BUCKETS - 1`.
+//!   - Return: The number of commands whose gap since the previous command
+//!     fell in that bucket, as a `u32`, or `INVAL` for an unknown bucket.
+//!     Bucket `i` counts gaps below `LATENCY_BUCKET_LIMITS_MS[i]`
+//!     milliseconds; the last bucket counts everything slower.
+//! - `17`: Reset the latency histogram.
+//!   - `data`: Unused.
+//!   - Return: `Ok(())`.
+//!
+//!
+//! ### Subscribe
+//!
+//! #### `subscribe_num`
+//!
+//! - `0`: Set the upcall for a successful command `1`. It is called with the
+//!   matched value as its first argument.
 //!
 //! Example
 //! -------
 //!
 //! ```rust
-//! // Instantiate the LifeDriver
-//! let life_driver = capsules::life::LifeDriver::new();
+//! // Instantiate the LifeDriver, timing commands with the board's RTC
+//! let life_driver = capsules::life::LifeDriver::new(
+//!     &rtc,
+//!     board_kernel.create_grant(capsules::life::DRIVER_NUM, &grant_cap),
+//! );
 //!
 //! // Use the driver to get the meaning of life
 //! let result = life_driver.command(0, 0, 0, ProcessId::new(0)); // This should return 42 as a u32
@@ -47,6 +83,10 @@
 //! let check_result = life_driver.command(1, 42, 0, ProcessId::new(0)); // This should return Ok(())
 //! ```
 
+use core::cell::Cell;
+
+use kernel::grant::{AllowRoCount, AllowRwCount, Grant, UpcallCount};
+use kernel::hil::time::{ConvertTicks, Ticks, Time};
 use kernel::syscall::{CommandReturn, SyscallDriver};
 use kernel::{ErrorCode, ProcessId};
 
@@ -55,38 +95,240 @@ use crate::driver;
 pub const DRIVER_NUM: usize = driver::NUM::Life as usize;
 pub const LIFE: usize = 42;
 
+/// Ids for subscribed upcalls.
+mod upcall {
+    /// A command `1` matched the meaning of life.
+    pub const MATCH: usize = 0;
+    /// Number of upcalls.
+    pub const COUNT: u8 = 1;
+}
+
+/// Initial value of the rolling CRC, before any command has been received.
+const CRC_INIT: u32 = 0xFFFF_FFFF;
+
+/// Fold `bytes` into a running CRC-32 (IEEE 802.3, reflected polynomial
+/// `0xEDB88320`). The final XOR is left to the caller.
+fn crc32_update(mut crc: u32, bytes: &[u8]) -> u32 {
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    crc
+}
+
+/// Number of buckets in the command latency histogram.
+pub const LATENCY_BUCKETS: usize = 5;
+
+/// Upper bound (exclusive, in milliseconds) of every latency bucket but the
+/// last, which collects all slower gaps.
+pub const LATENCY_BUCKET_LIMITS_MS: [u32; LATENCY_BUCKETS - 1] = [1, 10, 100, 1000];
+
+/// Per-process state.
+pub struct App {
+    /// The value command `1` checks against.
+    meaning: usize,
+    /// Number of commands this process has issued.
+    calls: u32,
+}
+
+impl Default for App {
+    fn default() -> Self {
+        App {
+            meaning: LIFE,
+            calls: 0,
+        }
+    }
+}
+
+impl App {
+    /// Count one more command from this process.
+    fn record_call(&mut self) {
+        self.calls = self.calls.wrapping_add(1);
+    }
+
+    /// Handle command `1` with `data`. If `data` is this process's meaning
+    /// of life, `schedule` is called with the arguments of the match upcall.
+    fn check_meaning<F: FnOnce((usize, usize, usize))>(
+        &self,
+        data: usize,
+        schedule: F,
+    ) -> Result<(), ErrorCode> {
+        if data != self.meaning {
+            return Err(ErrorCode::INVAL); /* data is not life */
+        }
+        schedule((data, 0, 0));
+        Ok(())
+    }
+}
+
+/// Bookkeeping over the sequence of commands a `LifeDriver` receives.
+pub struct CommandStats<'a, T: Time> {
+    /// Time source used to measure the gap between commands.
+    time: &'a T,
+    /// Running CRC over the arguments of every command received.
+    crc: Cell<u32>,
+    /// When the last timed command arrived.
+    last_command: Cell<Option<T::Ticks>>,
+    /// Histogram of gaps between successive commands.
+    latency: [Cell<u32>; LATENCY_BUCKETS],
+}
+
+impl<'a, T: Time> CommandStats<'a, T> {
+    pub fn new(time: &'a T) -> Self {
+        Self {
+            time,
+            crc: Cell::new(CRC_INIT),
+            last_command: Cell::new(None),
+            latency: Default::default(),
+        }
+    }
+
+    /// Fold the arguments of one command into the rolling CRC.
+    fn record_command(&self, command_num: usize, data: usize, arg2: usize) {
+        let mut crc = self.crc.get();
+        for word in [command_num, data, arg2] {
+            crc = crc32_update(crc, &(word as u32).to_le_bytes());
+        }
+        self.crc.set(crc);
+    }
+
+    /// The CRC-32 of every command argument received so far.
+    pub fn rolling_crc(&self) -> u32 {
+        !self.crc.get()
+    }
+
+    /// Bin the time since the previous command into the latency histogram.
+    fn record_latency(&self) {
+        let now = self.time.now();
+        if let Some(last) = self.last_command.get() {
+            let elapsed_ms = self.time.ticks_to_ms(now.wrapping_sub(last));
+            let bucket = LATENCY_BUCKET_LIMITS_MS
+                .iter()
+                .position(|&limit| elapsed_ms < limit)
+                .unwrap_or(LATENCY_BUCKETS - 1);
+            let count = &self.latency[bucket];
+            count.set(count.get().saturating_add(1));
+        }
+        self.last_command.set(Some(now));
+    }
+
+    /// Number of command gaps recorded in latency bucket `bucket`.
+    pub fn latency_bucket(&self, bucket: usize) -> Option<u32> {
+        self.latency.get(bucket).map(Cell::get)
+    }
+
+    /// Clear the latency histogram. The next command starts a new interval.
+    pub fn reset_latency(&self) {
+        self.latency.iter().for_each(|count| count.set(0));
+        self.last_command.set(None);
+    }
+}
+
 /// Implements a basic SyscallDriver without any specific device management.
-pub struct LifeDriver;
+pub struct LifeDriver<'a, T: Time> {
+    stats: CommandStats<'a, T>,
+    apps: Grant<App, UpcallCount<{ upcall::COUNT }>, AllowRoCount<0>, AllowRwCount<0>>,
+}
+
+impl<'a, T: Time> LifeDriver<'a, T> {
+    pub fn new(
+        time: &'a T,
+        grant: Grant<App, UpcallCount<{ upcall::COUNT }>, AllowRoCount<0>, AllowRwCount<0>>,
+    ) -> Self {
+        Self {
+            stats: CommandStats::new(time),
+            apps: grant,
+        }
+    }
 
-impl LifeDriver {
-    pub fn new() -> Self {
-        // Initialization logic can be added if needed in the future.
-        Self
+    /// The rolling CRC and latency histogram of the commands received.
+    pub fn stats(&self) -> &CommandStats<'a, T> {
+        &self.stats
     }
 }
 
-impl SyscallDriver for LifeDriver {
+impl<'a, T: Time> SyscallDriver for LifeDriver<'a, T> {
     /// Return the meaning of life
     ///
     /// ### `command_num`
     ///
     /// - `0`: Returns the meaning of life (42) as a u32. This is a simple
-    ///        example of a command that returns data.
-    /// - `1`: Returns a failure code if the data is not 42. This is a simple
-    ///        example of a command that returns a failure code.
+    ///   example of a command that returns data.
+    /// - `1`: Returns a failure code if the data is not the process's
+    ///   meaning of life (42 unless set with `2`). This is a simple example
+    ///   of a command that returns a failure code. On a match the subscribed
+    ///   upcall is scheduled.
+    /// - `2`: Sets the value command `1` checks against for this process.
+    /// - `3`: Returns how many commands this process has issued.
+    /// - `15`: Returns the rolling CRC of all previously received commands.
+    /// - `16`: Returns the count in latency histogram bucket `data`.
+    /// - `17`: Resets the latency histogram.
     ///
-    fn command(&self, command_num: usize, data: usize, _: usize, _: ProcessId) -> CommandReturn {
+    fn command(
+        &self,
+        command_num: usize,
+        data: usize,
+        arg2: usize,
+        processid: ProcessId,
+    ) -> CommandReturn {
+        if command_num != 15 {
+            self.stats.record_command(command_num, data, arg2);
+        }
+        if command_num != 16 && command_num != 17 {
+            self.stats.record_latency();
+        }
+        let _ = self.apps.enter(processid, |app, _| app.record_call());
+
         match command_num {
             // return the meaning of life
             0 => CommandReturn::success_u32(LIFE as u32),
 
-            // return a failure code if the data is not 42
-            1 => {
-                if data != LIFE {
-                    CommandReturn::failure(ErrorCode::INVAL) /* data is not life */
-                } else {
+            // return a failure code if the data is not this process's
+            // meaning of life, and let the process know through its upcall
+            // if it is
+            1 => self
+                .apps
+                .enter(processid, |app, kernel_data| {
+                    app.check_meaning(data, |args| {
+                        kernel_data.schedule_upcall(upcall::MATCH, args).ok();
+                    })
+                    .into()
+                })
+                .unwrap_or_else(|err| CommandReturn::failure(err.into())),
+
+            // change the meaning of life for this process
+            2 => self
+                .apps
+                .enter(processid, |app, _| {
+                    app.meaning = data;
                     CommandReturn::success()
-                }
+                })
+                .unwrap_or_else(|err| CommandReturn::failure(err.into())),
+
+            // return how many commands this process has issued
+            3 => self
+                .apps
+                .enter(processid, |app, _| CommandReturn::success_u32(app.calls))
+                .unwrap_or_else(|err| CommandReturn::failure(err.into())),
+
+            // return the rolling CRC of everything received so far
+            15 => CommandReturn::success_u32(self.stats.rolling_crc()),
+
+            // return one bucket of the latency histogram
+            16 => self
+                .stats
+                .latency_bucket(data)
+                .map_or(CommandReturn::failure(ErrorCode::INVAL), |count| {
+                    CommandReturn::success_u32(count)
+                }),
+
+            // clear the latency histogram
+            17 => {
+                self.stats.reset_latency();
+                CommandReturn::success()
             }
 
             // default
@@ -94,7 +336,147 @@ impl SyscallDriver for LifeDriver {
         }
     }
 
-    fn allocate_grant(&self, _processid: ProcessId) -> Result<(), kernel::process::Error> {
-        Ok(())
+    fn allocate_grant(&self, processid: ProcessId) -> Result<(), kernel::process::Error> {
+        self.apps.enter(processid, |_, _| {})
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kernel::hil::time::{Freq1KHz, Ticks32};
+
+    /// A 1 kHz clock that only moves when told to.
+    struct MockTime {
+        now: Cell<u32>,
+    }
+
+    impl MockTime {
+        fn new() -> Self {
+            MockTime { now: Cell::new(0) }
+        }
+
+        fn advance_ms(&self, ms: u32) {
+            self.now.set(self.now.get().wrapping_add(ms));
+        }
+    }
+
+    impl Time for MockTime {
+        type Frequency = Freq1KHz;
+        type Ticks = Ticks32;
+
+        fn now(&self) -> Ticks32 {
+            self.now.get().into()
+        }
+    }
+
+    // The `app_*` tests cover the `App` helpers only. Capsule tests cannot
+    // create a `Grant` or `ProcessId`, so entering the grant in `command` and
+    // keeping state apart between processes are not tested here.
+
+    type UpcallArgs = (usize, usize, usize);
+
+    /// Run command `1` against `app`, returning its result and the upcall
+    /// arguments it scheduled, if any.
+    fn check(app: &App, data: usize) -> (Result<(), ErrorCode>, Option<UpcallArgs>) {
+        let scheduled = Cell::new(None);
+        let result = app.check_meaning(data, |args| scheduled.set(Some(args)));
+        (result, scheduled.get())
+    }
+
+    #[test]
+    fn app_check_meaning_schedules_upcall_only_on_match() {
+        let app = App::default();
+        assert_eq!(check(&app, LIFE), (Ok(()), Some((LIFE, 0, 0))));
+        assert_eq!(check(&app, 0), (Err(ErrorCode::INVAL), None));
+        assert_eq!(check(&app, 41), (Err(ErrorCode::INVAL), None));
+    }
+
+    #[test]
+    fn app_set_meaning_only_changes_that_app() {
+        let mut a = App::default();
+        let b = App::default();
+
+        a.meaning = 7;
+        assert_eq!(check(&a, 7), (Ok(()), Some((7, 0, 0))));
+        assert_eq!(check(&a, LIFE), (Err(ErrorCode::INVAL), None));
+
+        // Another app's state still checks against the default.
+        assert_eq!(check(&b, 7), (Err(ErrorCode::INVAL), None));
+        assert_eq!(check(&b, LIFE), (Ok(()), Some((LIFE, 0, 0))));
+    }
+
+    #[test]
+    fn app_record_call_only_counts_that_app() {
+        let mut a = App::default();
+        let mut b = App::default();
+
+        a.record_call();
+        a.record_call();
+        b.record_call();
+        assert_eq!(a.calls, 2);
+        assert_eq!(b.calls, 1);
+    }
+
+    #[test]
+    fn crc32_known_vector() {
+        assert_eq!(!crc32_update(CRC_INIT, b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn rolling_crc_tracks_command_sequence() {
+        let time = MockTime::new();
+        let life = CommandStats::new(&time);
+        assert_eq!(life.rolling_crc(), 0);
+
+        life.record_command(0, 0, 0);
+        life.record_command(1, 42, 0);
+        life.record_command(1, 7, 3);
+
+        let mut expected = [0u8; 36];
+        for (i, word) in [0u32, 0, 0, 1, 42, 0, 1, 7, 3].iter().enumerate() {
+            expected[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+        }
+        assert_eq!(life.rolling_crc(), !crc32_update(CRC_INIT, &expected));
+    }
+
+    #[test]
+    fn rolling_crc_depends_on_order() {
+        let time = MockTime::new();
+        let a = CommandStats::new(&time);
+        a.record_command(0, 0, 0);
+        a.record_command(1, 42, 0);
+
+        let b = CommandStats::new(&time);
+        b.record_command(1, 42, 0);
+        b.record_command(0, 0, 0);
+
+        assert_ne!(a.rolling_crc(), b.rolling_crc());
+    }
+
+    #[test]
+    fn latency_histogram_buckets() {
+        let time = MockTime::new();
+        let life = CommandStats::new(&time);
+
+        // The first command only starts the clock.
+        life.record_latency();
+        for gap_ms in [0, 5, 50, 500, 5000, 9, 1000] {
+            time.advance_ms(gap_ms);
+            life.record_latency();
+        }
+
+        let counts: [u32; LATENCY_BUCKETS] =
+            core::array::from_fn(|i| life.latency_bucket(i).unwrap());
+        assert_eq!(counts, [1, 2, 1, 1, 2]);
+        assert_eq!(life.latency_bucket(LATENCY_BUCKETS), None);
+
+        life.reset_latency();
+        assert!((0..LATENCY_BUCKETS).all(|i| life.latency_bucket(i) == Some(0)));
+
+        // After a reset the next command starts a fresh interval.
+        time.advance_ms(5000);
+        life.record_latency();
+        assert_eq!(life.latency_bucket(LATENCY_BUCKETS - 1), Some(0));
     }
 }
@@ -0,0 +1,306 @@
+// Licensed under the Apache License, Version 2.0 or the MIT License.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+// Copyright Tock Contributors 2022.
+
+//! A UART test double for capsule unit tests.
+//!
+//! `MockUart` implements the UART HIL but never completes an operation on
+//! its own. It holds on to the buffers a capsule hands it, and tests decide
+//! when each operation finishes:
+//!
+//! - [`MockUart::complete_tx`] finishes the transmit in flight, records the
+//!   bytes, and calls `transmitted_buffer` on the transmit client.
+//! - [`MockUart::inject_rx`] copies bytes into the armed receive buffer and
+//!   calls `received_buffer` on the receive client.
+//!
+//! Aborting an operation in flight returns `BUSY` and immediately makes its
+//! callback with `Err(CANCEL)`, as the UART HIL describes for a cancel that
+//! succeeds.
+//!
+//! ```rust,ignore
+//! let uart = MockUart::new();
+//! let capsule = UartCapsule::new(&uart, tx, rx, RxMode::Raw);
+//! uart.set_transmit_client(&capsule);
+//! uart.set_receive_client(&capsule);
+//!
+//! capsule.start_transmission(&[1, 2, 3])?;
+//! assert_eq!(uart.complete_tx(), [1, 2, 3]);
+//! ```
+
+extern crate std;
+
+use core::cell::{Cell, RefCell};
+use std::vec::Vec;
+
+use kernel::hil::uart;
+use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::ErrorCode;
+
+pub struct MockUart<'a> {
+    tx_client: OptionalCell<&'a dyn uart::TransmitClient>,
+    rx_client: OptionalCell<&'a dyn uart::ReceiveClient>,
+    tx: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    tx_word: Cell<Option<u32>>,
+    rx: TakeCell<'static, [u8]>,
+    rx_len: Cell<usize>,
+    rx_word: Cell<bool>,
+    transmitted: RefCell<Vec<u8>>,
+}
+
+impl<'a> MockUart<'a> {
+    pub fn new() -> Self {
+        MockUart {
+            tx_client: OptionalCell::empty(),
+            rx_client: OptionalCell::empty(),
+            tx: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            tx_word: Cell::new(None),
+            rx: TakeCell::empty(),
+            rx_len: Cell::new(0),
+            rx_word: Cell::new(false),
+            transmitted: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Whether a buffer or word transmit is in flight.
+    pub fn transmitting(&self) -> bool {
+        self.tx.is_some() || self.tx_word.get().is_some()
+    }
+
+    /// The bytes of the buffer transmit in flight, if any.
+    pub fn pending_tx(&self) -> Option<Vec<u8>> {
+        let len = self.tx_len.get();
+        self.tx.map(|tx| tx[..len].to_vec())
+    }
+
+    /// The word transmit in flight, if any.
+    pub fn pending_tx_word(&self) -> Option<u32> {
+        self.tx_word.get()
+    }
+
+    /// Finish the buffer transmit in flight and return the bytes it sent.
+    ///
+    /// Panics if no buffer transmit is in flight.
+    pub fn complete_tx(&self) -> Vec<u8> {
+        let tx = self.tx.take().expect("no transmit in flight");
+        let len = self.tx_len.get();
+        let sent = tx[..len].to_vec();
+        self.transmitted.borrow_mut().extend_from_slice(&sent);
+        self.tx_client
+            .map(move |client| client.transmitted_buffer(tx, len, Ok(())));
+        sent
+    }
+
+    /// Finish the word transmit in flight and return the word it sent.
+    ///
+    /// Panics if no word transmit is in flight.
+    pub fn complete_tx_word(&self) -> u32 {
+        let word = self.tx_word.take().expect("no word transmit in flight");
+        self.transmitted.borrow_mut().push(word as u8);
+        self.tx_client.map(|client| client.transmitted_word(Ok(())));
+        word
+    }
+
+    /// Every byte sent by completed transmits, in order.
+    pub fn transmitted(&self) -> Vec<u8> {
+        self.transmitted.borrow().clone()
+    }
+
+    /// The length of the armed buffer receive, if any.
+    pub fn pending_rx_len(&self) -> Option<usize> {
+        self.rx.map(|_| self.rx_len.get())
+    }
+
+    /// Whether a word receive is armed.
+    pub fn rx_word_armed(&self) -> bool {
+        self.rx_word.get()
+    }
+
+    /// Complete the armed buffer receive with `data`.
+    ///
+    /// Panics if no buffer receive is armed or `data` is longer than the
+    /// requested length.
+    pub fn inject_rx(&self, data: &[u8]) {
+        let rx = self.rx.take().expect("no receive armed");
+        assert!(data.len() <= self.rx_len.get(), "more data than requested");
+        rx[..data.len()].copy_from_slice(data);
+        self.rx_client
+            .map(move |client| client.received_buffer(rx, data.len(), Ok(()), uart::Error::None));
+    }
+
+    /// Complete the armed word receive with `word`, reporting `error`.
+    ///
+    /// Panics if no word receive is armed.
+    pub fn inject_rx_word(&self, word: u32, error: uart::Error) {
+        assert!(self.rx_word.replace(false), "no word receive armed");
+        self.rx_client
+            .map(|client| client.received_word(word, Ok(()), error));
+    }
+}
+
+impl Default for MockUart<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Rejects a zero baud rate with `INVAL` and accepts everything else.
+impl uart::Configure for MockUart<'_> {
+    fn configure(&self, params: uart::Parameters) -> Result<(), ErrorCode> {
+        if params.baud_rate == 0 {
+            Err(ErrorCode::INVAL)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<'a> uart::Transmit<'a> for MockUart<'a> {
+    fn set_transmit_client(&self, client: &'a dyn uart::TransmitClient) {
+        self.tx_client.set(client);
+    }
+
+    fn transmit_buffer(
+        &self,
+        tx_buffer: &'static mut [u8],
+        tx_len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.transmitting() {
+            return Err((ErrorCode::BUSY, tx_buffer));
+        }
+        self.tx.replace(tx_buffer);
+        self.tx_len.set(tx_len);
+        Ok(())
+    }
+
+    fn transmit_word(&self, word: u32) -> Result<(), ErrorCode> {
+        if self.transmitting() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.tx_word.set(Some(word));
+        Ok(())
+    }
+
+    fn transmit_abort(&self) -> Result<(), ErrorCode> {
+        if let Some(tx) = self.tx.take() {
+            let len = self.tx_len.get();
+            self.tx_client
+                .map(move |client| client.transmitted_buffer(tx, len, Err(ErrorCode::CANCEL)));
+        } else if self.tx_word.take().is_some() {
+            self.tx_client
+                .map(|client| client.transmitted_word(Err(ErrorCode::CANCEL)));
+        } else {
+            return Ok(());
+        }
+        Err(ErrorCode::BUSY)
+    }
+}
+
+/// Records the byte as transmitted immediately.
+impl uart::TransmitSync for MockUart<'_> {
+    fn transmit_byte_sync(&self, byte: u8) {
+        self.transmitted.borrow_mut().push(byte);
+    }
+}
+
+impl<'a> uart::Receive<'a> for MockUart<'a> {
+    fn set_receive_client(&self, client: &'a dyn uart::ReceiveClient) {
+        self.rx_client.set(client);
+    }
+
+    fn receive_buffer(
+        &self,
+        rx_buffer: &'static mut [u8],
+        rx_len: usize,
+    ) -> Result<(), (ErrorCode, &'static mut [u8])> {
+        if self.rx.is_some() || self.rx_word.get() {
+            return Err((ErrorCode::BUSY, rx_buffer));
+        }
+        self.rx.replace(rx_buffer);
+        self.rx_len.set(rx_len);
+        Ok(())
+    }
+
+    fn receive_word(&self) -> Result<(), ErrorCode> {
+        if self.rx.is_some() || self.rx_word.get() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.rx_word.set(true);
+        Ok(())
+    }
+
+    fn receive_abort(&self) -> Result<(), ErrorCode> {
+        if let Some(rx) = self.rx.take() {
+            self.rx_client.map(move |client| {
+                client.received_buffer(rx, 0, Err(ErrorCode::CANCEL), uart::Error::Aborted)
+            });
+        } else if self.rx_word.replace(false) {
+            self.rx_client.map(|client| {
+                client.received_word(0, Err(ErrorCode::CANCEL), uart::Error::Aborted)
+            });
+        } else {
+            return Ok(());
+        }
+        Err(ErrorCode::BUSY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::boxed::Box;
+    use uart::{Receive, Transmit};
+
+    /// Records the result of every callback it receives.
+    #[derive(Default)]
+    struct Client {
+        tx: Cell<Option<Result<(), ErrorCode>>>,
+        rx: Cell<Option<(usize, Result<(), ErrorCode>)>>,
+    }
+
+    impl uart::TransmitClient for Client {
+        fn transmitted_buffer(&self, _: &'static mut [u8], _: usize, rval: Result<(), ErrorCode>) {
+            self.tx.set(Some(rval));
+        }
+    }
+
+    impl uart::ReceiveClient for Client {
+        fn received_buffer(
+            &self,
+            _: &'static mut [u8],
+            rx_len: usize,
+            rval: Result<(), ErrorCode>,
+            _: uart::Error,
+        ) {
+            self.rx.set(Some((rx_len, rval)));
+        }
+    }
+
+    fn buffer() -> &'static mut [u8] {
+        Box::leak(std::vec![0u8; 4].into_boxed_slice())
+    }
+
+    #[test]
+    fn abort_cancels_operation_in_flight() {
+        let uart = MockUart::new();
+        let client = Client::default();
+        uart.set_transmit_client(&client);
+        uart.set_receive_client(&client);
+
+        // Nothing outstanding: no callback.
+        assert_eq!(uart.transmit_abort(), Ok(()));
+        assert_eq!(uart.receive_abort(), Ok(()));
+        assert_eq!((client.tx.get(), client.rx.get()), (None, None));
+
+        assert!(uart.transmit_buffer(buffer(), 4).is_ok());
+        assert_eq!(uart.transmit_abort(), Err(ErrorCode::BUSY));
+        assert_eq!(client.tx.get(), Some(Err(ErrorCode::CANCEL)));
+        assert!(!uart.transmitting());
+
+        assert!(uart.receive_buffer(buffer(), 4).is_ok());
+        assert_eq!(uart.receive_abort(), Err(ErrorCode::BUSY));
+        assert_eq!(client.rx.get(), Some((0, Err(ErrorCode::CANCEL))));
+        assert_eq!(uart.pending_rx_len(), None);
+    }
+}
@@ -5,6 +5,8 @@
 pub mod alarm;
 pub mod alarm_edge_cases;
 pub mod double_grant_entry;
+#[cfg(test)]
+pub mod mock_uart;
 pub mod random_alarm;
 pub mod random_timer;
 pub mod rng;
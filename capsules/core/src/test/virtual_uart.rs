@@ -5,6 +5,8 @@
 //! Test reception on the virtualized UART: best if multiple Tests are
 //! instantiated and tested in parallel.
 
+use core::cell::Cell;
+
 use crate::virtualizers::virtual_uart::UartDevice;
 
 use kernel::debug;
@@ -19,6 +21,9 @@ use kernel::ErrorCode;
 pub struct TestVirtualUartReceive {
     device: &'static UartDevice<'static>,
     buffer: TakeCell<'static, [u8]>,
+    /// Number of receives to complete before stopping, `None` to run forever.
+    iterations: Option<usize>,
+    completed: Cell<usize>,
 }
 pub struct TestVirtualUartTransmit{
     device: &'static UartDevice<'static>,
@@ -45,9 +50,21 @@ impl TestVirtualUartTransmit {
 
 impl TestVirtualUartReceive {
     pub fn new(device: &'static UartDevice<'static>, buffer: &'static mut [u8]) -> Self {
+        Self::with_iterations(device, buffer, None)
+    }
+
+    /// Like `new`, but stops re-arming the receive after `iterations`
+    /// completed receives. `None` keeps receiving forever.
+    pub fn with_iterations(
+        device: &'static UartDevice<'static>,
+        buffer: &'static mut [u8],
+        iterations: Option<usize>,
+    ) -> Self {
         TestVirtualUartReceive {
             device: device,
             buffer: TakeCell::new(buffer),
+            iterations,
+            completed: Cell::new(0),
         }
     }
 
@@ -81,6 +98,13 @@ impl uart::ReceiveClient for TestVirtualUartReceive {
         for i in 0..rx_len {
             debug!("{:02x} ", rx_buffer[i]);
         }
+        let completed = self.completed.get() + 1;
+        self.completed.set(completed);
+        if self.iterations.is_some_and(|n| completed >= n) {
+            debug!("receive test complete.");
+            self.buffer.replace(rx_buffer);
+            return;
+        }
         debug!("Starting receive of length {}", rx_len);
         self.device
             .receive_buffer(rx_buffer, rx_len)
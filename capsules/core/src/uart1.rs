@@ -45,7 +45,6 @@
 //! hil::uart::UART::set_receive_client(console_uart, console);
 //! ```
 
-use crate::virtualizers::virtual_uart::UartDevice;
 use core::cell::Cell;
 use core::cmp;
 use core::fmt::Error;
@@ -55,64 +54,176 @@ use kernel::collections::list::{List, ListLink, ListNode};
 use kernel::deferred_call::{DeferredCall, DeferredCallClient};
 use kernel::hil::gpio;
 use kernel::hil::uart;
-use kernel::hil::uart::{Receive, Transmit};
-use kernel::utilities::cells::{OptionalCell, TakeCell};
+use kernel::utilities::cells::{MapCell, OptionalCell, TakeCell};
 use kernel::ErrorCode;
 
 pub const RX_BUF_LEN: usize = 64;
+/// Number of messages that can wait behind the one being transmitted.
+pub const TX_QUEUE_LEN: usize = 4;
+/// Longest message that can be queued.
+pub const TX_QUEUE_MSG_LEN: usize = 20;
 
-pub struct UartCapsule {
-    device: &'static UartDevice<'static>,
+/// Sent to ask the peer to resume transmitting (DC1).
+pub const XON: u8 = 0x11;
+/// Sent to ask the peer to pause transmitting (DC3).
+pub const XOFF: u8 = 0x13;
+
+/// Line settings assumed until `configure` is called: 115200 baud, 8N1.
+pub const DEFAULT_PARAMETERS: uart::Parameters = uart::Parameters {
+    baud_rate: 115200,
+    width: uart::Width::Eight,
+    parity: uart::Parity::None,
+    stop_bits: uart::StopBits::One,
+    hw_flow_control: false,
+};
+
+/// What `UartCapsule` does with a received buffer.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RxMode {
+    /// Only buffer the received bytes for `read`.
+    #[default]
+    Raw,
+    /// Also echo the message back with its first byte incremented by one,
+    /// and wait for the next message once the echo has been sent.
+    PongIncrement,
+}
+
+pub struct UartCapsule<'a, U: uart::UartData<'a>> {
+    device: &'a U,
     tx_buffer: TakeCell<'static, [u8]>,
     rx_buffer: TakeCell<'static, [u8]>,
-    // tx_in_progress: Cell<bool>,
-    // rx_in_progress: Cell<bool>,
+    rx_mode: RxMode,
+    /// Log a trace line for every transfer. Off by default, as console output
+    /// is far slower than the UART transfers it would describe.
+    verbose: Cell<bool>,
+    /// Re-arm the receive as soon as a buffer has been processed.
+    continuous: Cell<bool>,
+    /// A transmit has been handed to the device and not yet completed.
+    tx_in_progress: Cell<bool>,
+    /// A receive has been handed to the device and not yet completed.
+    rx_in_progress: Cell<bool>,
+    /// The last receive was a `receive_word`, so pong re-arms with words.
+    rx_words: Cell<bool>,
+    /// Line settings last applied through `configure`.
+    parameters: Cell<uart::Parameters>,
+    /// Received bytes waiting to be collected with `read`. One slot is kept
+    /// free to tell a full ring from an empty one.
+    rx_ring: MapCell<[u8; RX_BUF_LEN]>,
+    /// Index of the oldest unread byte in `rx_ring`.
+    rx_head: Cell<usize>,
+    /// Index where the next received byte is stored in `rx_ring`.
+    rx_tail: Cell<usize>,
+    /// Bytes discarded because `rx_ring` was full.
+    rx_dropped: Cell<usize>,
+    /// Messages submitted while a transmit was in progress, each stored with
+    /// its length. Sent in order as earlier transmits complete.
+    tx_queue: MapCell<[([u8; TX_QUEUE_MSG_LEN], usize); TX_QUEUE_LEN]>,
+    /// Index of the oldest queued message in `tx_queue`.
+    tx_queue_head: Cell<usize>,
+    /// Number of messages in `tx_queue`.
+    tx_queue_count: Cell<usize>,
+    /// Pattern sent by `run_loopback`, to compare against what comes back.
+    loopback: MapCell<[u8; TX_QUEUE_MSG_LEN]>,
+    /// Length of the pattern while a loopback test is running.
+    loopback_len: OptionalCell<usize>,
+    /// Outcome of the last loopback test that completed.
+    loopback_result: OptionalCell<Result<(), ErrorCode>>,
+    /// Software flow control watermarks `(high, low)`, in buffered bytes.
+    /// `None` when flow control is off.
+    flow_control: OptionalCell<(usize, usize)>,
+    /// XOFF has been sent and XON not yet.
+    xoff_sent: Cell<bool>,
     // tx_ready: &'a dyn kernel::hil::gpio::Pin,
     // rx_ready: &'a dyn kernel::hil::gpio::Pin,
 }
 
-impl UartCapsule {
+impl<'a, U: uart::UartData<'a>> UartCapsule<'a, U> {
     pub fn new(
-        device: &'static UartDevice,
+        device: &'a U,
         tx_buffer: &'static mut [u8],
 
         rx_buffer: &'static mut [u8],
+        rx_mode: RxMode,
         // tx_in_progress: Cell<bool>,
         // rx_in_progress: Cell<bool>,
         // tx_ready: &'a dyn kernel::hil::gpio::Pin,
         // rx_ready: &'a dyn kernel::hil::gpio::Pin,
-    ) -> UartCapsule {
+    ) -> UartCapsule<'a, U> {
         //
         UartCapsule {
             device: device,
             tx_buffer: TakeCell::new(tx_buffer),
             rx_buffer: TakeCell::new(rx_buffer),
+            rx_mode,
+            verbose: Cell::new(false),
+            continuous: Cell::new(false),
+            tx_in_progress: Cell::new(false),
+            rx_in_progress: Cell::new(false),
+            rx_words: Cell::new(false),
+            parameters: Cell::new(DEFAULT_PARAMETERS),
+            rx_ring: MapCell::new([0; RX_BUF_LEN]),
+            rx_head: Cell::new(0),
+            rx_tail: Cell::new(0),
+            rx_dropped: Cell::new(0),
+            tx_queue: MapCell::new([([0; TX_QUEUE_MSG_LEN], 0); TX_QUEUE_LEN]),
+            tx_queue_head: Cell::new(0),
+            tx_queue_count: Cell::new(0),
+            loopback: MapCell::new([0; TX_QUEUE_MSG_LEN]),
+            loopback_len: OptionalCell::empty(),
+            loopback_result: OptionalCell::empty(),
+            flow_control: OptionalCell::empty(),
+            xoff_sent: Cell::new(false),
+        }
+    }
+
+    /// Enable or disable per-transfer debug output.
+    pub fn set_verbose(&self, verbose: bool) {
+        self.verbose.set(verbose);
+    }
+
+    /// Log `args` with `debug!` if verbose output is enabled.
+    fn trace(&self, args: core::fmt::Arguments) {
+        if self.verbose.get() {
+            debug!("UartCapsule: {}", args);
         }
     }
+
+    /// The line settings most recently applied with `configure`, or
+    /// `DEFAULT_PARAMETERS` if it has never been called.
+    pub fn parameters(&self) -> uart::Parameters {
+        self.parameters.get()
+    }
+    /// Transmit a copy of `buffer`. If a transmit is already in progress the
+    /// message is queued and sent once those before it complete; `NOMEM` is
+    /// returned when the queue is full.
     // buf should not take ownership of, should borrow, buffer
     pub fn start_transmission(&self, buffer: &[u8]) -> Result<(), ErrorCode> {
+        if self.tx_in_progress.get() {
+            return self.enqueue(buffer);
+        }
+        self.transmit_now(buffer)
+    }
+
+    fn transmit_now(&self, buffer: &[u8]) -> Result<(), ErrorCode> {
         // for byte in buffer copy into buf
         // debug!("[DEBUG] send() works!");
         self.tx_buffer
             .take()
             .map_or(Err(ErrorCode::BUSY), |tx_buf| {
-                for (i, c) in buffer.iter().enumerate() {
-                    // Don't need to account for mismatched data length
-                    if i < tx_buf.len() {
-                        tx_buf[i] = *c;
-                        // debug!("{}", tx_buf[i]);
-                    } else {
-                        debug!("buffer too big");
-                    }
+                // Refuse rather than silently truncate the message.
+                if buffer.len() > tx_buf.len() {
+                    self.tx_buffer.replace(tx_buf);
+                    return Err(ErrorCode::SIZE);
                 }
-                // let copy_len = dest.len().max(len);
-
-                // dest[0..copy_len].copy_from_slice(&buffer[0..copy_len]);
-                // }
-                let len = tx_buf.len();
+                let len = buffer.len();
+                tx_buf[..len].copy_from_slice(buffer);
                 let result = self.device.transmit_buffer(tx_buf, len);
                 match result {
-                    Ok(()) => Ok(()),
+                    Ok(()) => {
+                        self.tx_in_progress.set(true);
+                        self.trace(format_args!("transmitting {} bytes", len));
+                        Ok(())
+                    }
                     Err((code, buffer)) => {
                         self.tx_buffer.replace(buffer);
                         Err(code)
@@ -132,10 +243,16 @@ impl UartCapsule {
     //
     // UartCapsule.receive()
     // TODO
-    // 1) Continuous receiving
-    // 2) In-progress flags
-    // 3) Mismatch buffer lengths
+    // 1) Mismatch buffer lengths
     pub fn receive(&self) -> Result<(), ErrorCode> {
+        let len = self.rx_buffer.map_or(0, |rx_buf| rx_buf.len());
+        self.receive_len(len)
+    }
+
+    fn receive_len(&self, len: usize) -> Result<(), ErrorCode> {
+        if self.rx_in_progress.get() {
+            return Err(ErrorCode::BUSY);
+        }
         // Base Case 1: If the rx_buffer has something in it,
         // then we are able to actually receive stuff
         // if self.rx_buffer.is_none() {
@@ -144,11 +261,18 @@ impl UartCapsule {
         self.rx_buffer
             .take()
             .map_or(Err(ErrorCode::BUSY), |rx_buf| {
-                let len = rx_buf.len();
+                if len > rx_buf.len() {
+                    self.rx_buffer.replace(rx_buf);
+                    return Err(ErrorCode::SIZE);
+                }
                 let result: Result<(), (ErrorCode, &mut [u8])> =
                     self.device.receive_buffer(rx_buf, len);
                 match result {
-                    Ok(()) => Ok(()),
+                    Ok(()) => {
+                        self.rx_in_progress.set(true);
+                        self.rx_words.set(false);
+                        Ok(())
+                    }
                     Err((code, buffer)) => {
                         self.rx_buffer.replace(buffer);
                         Err(code)
@@ -156,9 +280,330 @@ impl UartCapsule {
                 }
             })
     }
+
+    /// Check the UART path without a second device by sending `pattern` and
+    /// expecting the same bytes back. Once the echo has been received the
+    /// outcome is logged with `debug!` and is available from
+    /// `loopback_result`.
+    ///
+    /// The board must loop TX back to RX, either by tying the two pins
+    /// together or by enabling a loopback mode in the UART hardware.
+    /// `pattern` must fit in both the tx and rx buffers and in
+    /// `TX_QUEUE_MSG_LEN`.
+    pub fn run_loopback(&self, pattern: &[u8]) -> Result<(), ErrorCode> {
+        if pattern.is_empty() || pattern.len() > TX_QUEUE_MSG_LEN {
+            return Err(ErrorCode::SIZE);
+        }
+        if self.loopback_len.is_some() || self.tx_in_progress.get() {
+            return Err(ErrorCode::BUSY);
+        }
+        // Check the transmit side up front, so that a pattern that cannot be
+        // sent never leaves a receive armed.
+        if pattern.len() > self.tx_buffer.map_or(0, |tx_buf| tx_buf.len()) {
+            return Err(ErrorCode::SIZE);
+        }
+        self.loopback
+            .map(|expected| expected[..pattern.len()].copy_from_slice(pattern));
+        self.loopback_result.clear();
+        // The echo arrives while the pattern is still being sent, so the
+        // receive has to be armed before the transmit starts.
+        self.receive_len(pattern.len())?;
+        self.loopback_len.set(pattern.len());
+        if let Err(code) = self.transmit_now(pattern) {
+            // On BUSY or FAIL the receive completes through `received_buffer`,
+            // which ends the test. On Ok(()) there is no receive to cancel
+            // and no callback, so end it here.
+            if self.device.receive_abort() == Ok(()) {
+                self.rx_in_progress.set(false);
+                self.loopback_len.clear();
+                self.loopback_result.set(Err(code));
+            }
+            return Err(code);
+        }
+        Ok(())
+    }
+
+    /// The outcome of the last completed loopback test: `Ok(())` if the
+    /// pattern came back unchanged, `FAIL` if different bytes came back, or
+    /// the error the receive completed with. `None` while a test is running
+    /// or if none has been run.
+    pub fn loopback_result(&self) -> Option<Result<(), ErrorCode>> {
+        self.loopback_result.get()
+    }
+
+    /// Log the outcome of a loopback test. The test is run explicitly and
+    /// only once, so this does not depend on `verbose`.
+    fn log_loopback_result(
+        &self,
+        result: Result<(), ErrorCode>,
+        error: uart::Error,
+        received: &[u8],
+    ) {
+        // Host unit tests have no debug writer; they check `loopback_result`.
+        if cfg!(test) {
+            return;
+        }
+        match result {
+            Ok(()) => debug!("UartCapsule: loopback passed"),
+            Err(code) => debug!(
+                "UartCapsule: loopback failed: {:?} {:?} {:?}",
+                code, error, received
+            ),
+        }
+    }
+
+    /// Whether `received` is the pattern sent by the running loopback test.
+    fn loopback_matches(&self, received: &[u8]) -> bool {
+        self.loopback_len.map_or(false, |len| {
+            self.loopback
+                .map_or(false, |expected| received == &expected[..len])
+        })
+    }
+
+    /// Receive a single character. The device reports it through
+    /// `received_word`, after which it is available from `read`.
+    pub fn receive_word(&self) -> Result<(), ErrorCode> {
+        if self.rx_in_progress.get() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.device.receive_word()?;
+        self.rx_in_progress.set(true);
+        self.rx_words.set(true);
+        Ok(())
+    }
+
+    /// Transmit a single character, for devices that work a word at a time.
+    pub fn transmit_word(&self, word: u32) -> Result<(), ErrorCode> {
+        if self.tx_in_progress.get() {
+            return Err(ErrorCode::BUSY);
+        }
+        self.device.transmit_word(word)?;
+        self.tx_in_progress.set(true);
+        Ok(())
+    }
+
+    /// Start receiving and keep listening: every time a buffer has been
+    /// received and processed, the receive is issued again with the same
+    /// buffer.
+    pub fn start_continuous_receive(&self) -> Result<(), ErrorCode> {
+        self.continuous.set(true);
+        self.receive()
+    }
+
+    /// Stop re-arming the receive after the one currently in flight.
+    pub fn stop_continuous_receive(&self) {
+        self.continuous.set(false);
+    }
+
+    /// Turn on XON/XOFF flow control. XOFF is sent once `high` received
+    /// bytes are waiting to be read, and XON once `read` has drained them to
+    /// `low` or fewer. Both are sent with `start_transmission`.
+    ///
+    /// Returns `INVAL` unless `low < high < RX_BUF_LEN`.
+    pub fn enable_flow_control(&self, high: usize, low: usize) -> Result<(), ErrorCode> {
+        if low >= high || high >= RX_BUF_LEN {
+            return Err(ErrorCode::INVAL);
+        }
+        self.flow_control.set((high, low));
+        Ok(())
+    }
+
+    /// Turn off XON/XOFF flow control. An XOFF already sent is not undone.
+    pub fn disable_flow_control(&self) {
+        self.flow_control.clear();
+        self.xoff_sent.set(false);
+    }
+
+    /// Number of received bytes waiting to be collected with `read`.
+    fn buffered(&self) -> usize {
+        (self.rx_tail.get() + RX_BUF_LEN - self.rx_head.get()) % RX_BUF_LEN
+    }
+
+    /// Send XOFF or XON if the ring has crossed a flow control watermark.
+    /// A control byte that cannot be queued is retried on the next call.
+    fn update_flow_control(&self) {
+        self.flow_control.map(|(high, low)| {
+            let buffered = self.buffered();
+            let (byte, xoff_sent) = if !self.xoff_sent.get() && buffered >= high {
+                (XOFF, true)
+            } else if self.xoff_sent.get() && buffered <= low {
+                (XON, false)
+            } else {
+                return;
+            };
+            if self.start_transmission(&[byte]).is_ok() {
+                self.xoff_sent.set(xoff_sent);
+            }
+        });
+    }
+
+    /// Move buffered received bytes into `out`, oldest first. Returns the
+    /// number of bytes copied.
+    pub fn read(&self, out: &mut [u8]) -> usize {
+        let count = self
+            .rx_ring
+            .map(|ring| {
+                let mut head = self.rx_head.get();
+                let tail = self.rx_tail.get();
+                let mut count = 0;
+                while head != tail && count < out.len() {
+                    out[count] = ring[head];
+                    head = (head + 1) % RX_BUF_LEN;
+                    count += 1;
+                }
+                self.rx_head.set(head);
+                count
+            })
+            .unwrap_or(0);
+        self.update_flow_control();
+        count
+    }
+
+    /// Copy a message to the back of the transmit queue.
+    fn enqueue(&self, buffer: &[u8]) -> Result<(), ErrorCode> {
+        if buffer.len() > TX_QUEUE_MSG_LEN {
+            return Err(ErrorCode::SIZE);
+        }
+        let count = self.tx_queue_count.get();
+        if count == TX_QUEUE_LEN {
+            return Err(ErrorCode::NOMEM);
+        }
+        self.tx_queue
+            .map(|queue| {
+                let (msg, len) = &mut queue[(self.tx_queue_head.get() + count) % TX_QUEUE_LEN];
+                msg[..buffer.len()].copy_from_slice(buffer);
+                *len = buffer.len();
+                self.tx_queue_count.set(count + 1);
+            })
+            .ok_or(ErrorCode::FAIL)
+    }
+
+    /// Start transmitting the oldest queued message, discarding any that the
+    /// device refuses.
+    fn transmit_queued(&self) {
+        while self.tx_queue_count.get() > 0 {
+            let head = self.tx_queue_head.get();
+            self.tx_queue_head.set((head + 1) % TX_QUEUE_LEN);
+            self.tx_queue_count.set(self.tx_queue_count.get() - 1);
+            let result = self
+                .tx_queue
+                .map(|queue| {
+                    let (msg, len) = &queue[head];
+                    self.transmit_now(&msg[..*len])
+                })
+                .unwrap_or(Err(ErrorCode::FAIL));
+            match result {
+                Ok(()) => return,
+                Err(code) => self.trace(format_args!("dropped queued message: {:?}", code)),
+            }
+        }
+    }
+
+    /// Number of received bytes discarded because the ring buffer was full.
+    pub fn dropped(&self) -> usize {
+        self.rx_dropped.get()
+    }
+
+    /// Append received bytes to the ring buffer, counting any that do not
+    /// fit.
+    fn buffer_received(&self, bytes: &[u8]) {
+        self.rx_ring.map(|ring| {
+            let mut tail = self.rx_tail.get();
+            for &byte in bytes {
+                let next = (tail + 1) % RX_BUF_LEN;
+                if next == self.rx_head.get() {
+                    self.rx_dropped.set(self.rx_dropped.get() + 1);
+                } else {
+                    ring[tail] = byte;
+                    tail = next;
+                }
+            }
+            self.rx_tail.set(tail);
+        });
+        self.update_flow_control();
+    }
+
+    /// Handle bytes from `received_buffer` or `received_word`.
+    ///
+    /// A running loopback test is ended with the result of comparing them to
+    /// its pattern. Otherwise they are buffered for `read`, and in pong mode
+    /// the echo is returned so the caller can send it after re-arming the
+    /// receive.
+    fn process_received(
+        &self,
+        received: &[u8],
+        rcode: Result<(), ErrorCode>,
+        error: uart::Error,
+    ) -> Option<([u8; TX_QUEUE_MSG_LEN], usize)> {
+        if self.loopback_len.is_some() {
+            let result = rcode.and_then(|()| {
+                if self.loopback_matches(received) {
+                    Ok(())
+                } else {
+                    Err(ErrorCode::FAIL)
+                }
+            });
+            self.log_loopback_result(result, error, received);
+            self.loopback_result.set(result);
+            self.loopback_len.clear();
+            return None;
+        }
+        self.buffer_received(received);
+
+        // For pong, echo a copy of the message with its first byte
+        // incremented. The received data itself is never modified.
+        match self.rx_mode {
+            RxMode::PongIncrement if !received.is_empty() => {
+                let mut echo = [0; TX_QUEUE_MSG_LEN];
+                let len = cmp::min(received.len(), echo.len());
+                echo[..len].copy_from_slice(&received[..len]);
+                echo[0] = echo[0].wrapping_add(1);
+                Some((echo, len))
+            }
+            _ => None,
+        }
+    }
+
+    /// Send a pong echo.
+    fn send_echo(&self, echo: &[u8]) {
+        match self.start_transmission(echo) {
+            Ok(()) => self.trace(format_args!("sending {} byte echo", echo.len())),
+            Err(code) => self.trace(format_args!("echo not sent: {:?}", code)),
+        }
+    }
+}
+
+impl<'a, U: uart::UartData<'a> + uart::Configure> UartCapsule<'a, U> {
+    /// Set the baud rate, parity and stop bits of the underlying device.
+    ///
+    /// Only available when the device implements `uart::Configure`. A
+    /// virtualized `UartDevice` does so by reconfiguring the UART behind its
+    /// `MuxUart`, so the settings apply to every device on that mux.
+    pub fn configure(&self, params: uart::Parameters) -> Result<(), ErrorCode> {
+        self.device.configure(params)?;
+        self.parameters.set(params);
+        Ok(())
+    }
+}
+
+impl<'a, U: uart::UartData<'a> + uart::TransmitSync> UartCapsule<'a, U> {
+    /// Send `data` by polling the device, for panic or early boot logging
+    /// when no main loop is running to deliver `transmitted_buffer`.
+    ///
+    /// Only available when the device implements `uart::TransmitSync`. A
+    /// virtualized `UartDevice` does so through its `MuxUart`, once the board
+    /// has called `MuxUart::set_transmit_sync`. It must only be used while
+    /// interrupts are effectively disabled. It bypasses the transmit queue
+    /// and the client callbacks, and does not wait for a transmit already
+    /// in flight.
+    pub fn transmit_sync(&self, data: &[u8]) {
+        for &byte in data {
+            self.device.transmit_byte_sync(byte);
+        }
+    }
 }
 
-impl uart::TransmitClient for UartCapsule {
+impl<'a, U: uart::UartData<'a>> uart::TransmitClient for UartCapsule<'a, U> {
     fn transmitted_buffer(
         &self,
         buffer: &'static mut [u8],
@@ -166,19 +611,39 @@ impl uart::TransmitClient for UartCapsule {
         rval: Result<(), ErrorCode>,
     ) {
         self.tx_buffer.replace(buffer);
+        self.tx_in_progress.set(false);
+        self.transmit_queued();
 
-        // for pong: call self.receive()
-        let result = self.receive();
+        // Only pong waits for the next message after replying, and in
+        // continuous mode the receive has already been re-armed. A loopback
+        // test armed its own receive before transmitting.
+        if self.rx_mode != RxMode::PongIncrement
+            || self.continuous.get()
+            || self.loopback_len.is_some()
+        {
+            return;
+        }
+
+        // for pong: receive the next message the same way as the last one
+        let result = if self.rx_words.get() {
+            self.receive_word()
+        } else {
+            self.receive()
+        };
         // debug!("started receiving :)");
 
         if let Err(code) = result {
-            debug!("{:?}", code);
+            self.trace(format_args!("receive not re-armed: {:?}", code));
         }
     }
-    fn transmitted_word(&self, _rval: Result<(), ErrorCode>) {}
+
+    fn transmitted_word(&self, _rval: Result<(), ErrorCode>) {
+        self.tx_in_progress.set(false);
+        self.transmit_queued();
+    }
 }
 
-impl uart::ReceiveClient for UartCapsule {
+impl<'a, U: uart::UartData<'a>> uart::ReceiveClient for UartCapsule<'a, U> {
     fn received_buffer(
         &self,
         buffer: &'static mut [u8],
@@ -186,19 +651,18 @@ impl uart::ReceiveClient for UartCapsule {
         rcode: Result<(), ErrorCode>,
         error: uart::Error,
     ) {
-        debug!("{}", buffer[0]);
-
-        // Print out what was received in transmission
-        buffer[0] += 1; // Increment the 0th value of the buffer for pong
-                        // self.send(buffer);
-
-        let mut new_buffer: [u8; 20] = [0; 20];
-
-        for (i, c) in buffer.iter().enumerate() {
-            new_buffer[i] = *c;
-        }
+        self.rx_in_progress.set(false);
+        self.trace(format_args!("received {} bytes", rx_len));
+        // A loopback test armed this receive itself, so it is not re-armed.
+        let rearm = self.continuous.get() && self.loopback_len.is_none();
+        let echo = self.process_received(&buffer[..rx_len], rcode, error);
 
         self.rx_buffer.replace(buffer);
+        if rearm {
+            if let Err(code) = self.receive() {
+                self.trace(format_args!("receive not re-armed: {:?}", code));
+            }
+        }
         // self.rx_buffer.replace(new_buffer);
         // Copy the contents of the original buffer into the new buffer
 
@@ -213,14 +677,471 @@ impl uart::ReceiveClient for UartCapsule {
         //     }
         // }
 
-        let transmission_result: Result<(), ErrorCode> = self.start_transmission(&new_buffer);
-        if let Err(code) = transmission_result {
-            debug!("{:?}", code);
-        } else {
-            debug!("transmit complete");
+        if let Some((echo, len)) = echo {
+            self.send_echo(&echo[..len]);
         }
         // check result/error code
     }
 
-    fn received_word(&self, _word: u32, _rval: Result<(), ErrorCode>, _error: uart::Error) {}
+    fn received_word(&self, word: u32, rval: Result<(), ErrorCode>, error: uart::Error) {
+        self.rx_in_progress.set(false);
+        // A word carries one character, which is at most eight bits wide. A
+        // character received with an error is discarded.
+        let byte = [word as u8];
+        let received: &[u8] = if rval.is_ok() && error == uart::Error::None {
+            &byte
+        } else {
+            &[]
+        };
+        let rearm = self.continuous.get() && self.loopback_len.is_none();
+        let echo = self.process_received(received, rval, error);
+
+        if rearm {
+            if let Err(code) = self.receive_word() {
+                self.trace(format_args!("receive not re-armed: {:?}", code));
+            }
+        }
+        if let Some((echo, len)) = echo {
+            self.send_echo(&echo[..len]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::test::mock_uart::MockUart;
+    use crate::virtualizers::virtual_uart::{MuxUart, UartDevice};
+    use kernel::hil::uart::{Receive, Transmit};
+    use std::boxed::Box;
+
+    fn buffer(len: usize) -> &'static mut [u8] {
+        Box::leak(std::vec![0u8; len].into_boxed_slice())
+    }
+
+    #[test]
+    fn queued_messages_transmit_in_order() {
+        let uart = MockUart::new();
+        let capsule = UartCapsule::new(&uart, buffer(20), buffer(20), RxMode::Raw);
+        uart.set_transmit_client(&capsule);
+
+        assert_eq!(capsule.start_transmission(&[1, 2, 3]), Ok(()));
+        assert_eq!(capsule.start_transmission(&[4, 5]), Ok(()));
+        assert_eq!(capsule.start_transmission(&[6]), Ok(()));
+
+        assert_eq!(uart.complete_tx(), [1, 2, 3]);
+        assert_eq!(uart.complete_tx(), [4, 5]);
+        assert_eq!(uart.complete_tx(), [6]);
+        assert!(!uart.transmitting());
+        assert_eq!(uart.transmitted(), [1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn transmit_is_quiet_by_default() {
+        let uart = MockUart::new();
+        let capsule = UartCapsule::new(&uart, buffer(20), buffer(20), RxMode::Raw);
+        uart.set_transmit_client(&capsule);
+        assert!(!capsule.verbose.get());
+
+        // The message is handed to the UART as one buffer, with nothing sent
+        // or logged byte by byte (`debug!` would panic in a host test).
+        assert_eq!(capsule.start_transmission(&[1, 2, 3]), Ok(()));
+        assert_eq!(uart.pending_tx().unwrap(), [1, 2, 3]);
+        assert!(uart.transmitted().is_empty());
+        assert_eq!(uart.complete_tx(), [1, 2, 3]);
+        assert_eq!(uart.transmitted(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn failures_are_quiet_by_default() {
+        // Host tests have no debug writer, so any `debug!` would panic.
+        let uart = MockUart::new();
+        let capsule = UartCapsule::new(&uart, buffer(2), buffer(20), RxMode::PongIncrement);
+        uart.set_transmit_client(&capsule);
+        uart.set_receive_client(&capsule);
+
+        // The receive is still armed when the transmit completes, so pong
+        // fails to re-arm it.
+        assert_eq!(capsule.receive(), Ok(()));
+        assert_eq!(capsule.start_transmission(&[1, 2]), Ok(()));
+
+        // A queued message too long for the tx buffer is dropped.
+        assert_eq!(capsule.start_transmission(&[3, 4, 5]), Ok(()));
+        assert_eq!(uart.complete_tx(), [1, 2]);
+        assert!(!uart.transmitting());
+        assert_eq!(uart.pending_rx_len(), Some(20));
+    }
+
+    #[test]
+    fn full_transmit_queue_is_rejected() {
+        let uart = MockUart::new();
+        let capsule = UartCapsule::new(&uart, buffer(20), buffer(20), RxMode::Raw);
+        uart.set_transmit_client(&capsule);
+
+        // One message in flight plus a full queue behind it.
+        for i in 0..=TX_QUEUE_LEN as u8 {
+            assert_eq!(capsule.start_transmission(&[i]), Ok(()));
+        }
+        assert_eq!(capsule.start_transmission(&[9]), Err(ErrorCode::NOMEM));
+
+        // Completing a transmit frees a slot.
+        assert_eq!(uart.complete_tx(), [0]);
+        assert_eq!(capsule.start_transmission(&[9]), Ok(()));
+    }
+
+    #[test]
+    fn transmit_sends_only_copied_length() {
+        let uart = MockUart::new();
+        let capsule = UartCapsule::new(&uart, buffer(7), buffer(7), RxMode::Raw);
+
+        assert_eq!(capsule.start_transmission(&[1, 2, 3]), Ok(()));
+        assert_eq!(uart.pending_tx().unwrap(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn oversized_transmit_is_rejected() {
+        let uart = MockUart::new();
+        let capsule = UartCapsule::new(&uart, buffer(7), buffer(7), RxMode::Raw);
+
+        assert_eq!(capsule.start_transmission(&[0; 8]), Err(ErrorCode::SIZE));
+        assert!(!uart.transmitting());
+        // The tx buffer is kept for the next attempt.
+        assert_eq!(capsule.start_transmission(&[0; 7]), Ok(()));
+    }
+
+    #[test]
+    fn configure_records_parameters() {
+        let uart = MockUart::new();
+        let capsule = UartCapsule::new(&uart, buffer(7), buffer(7), RxMode::Raw);
+        assert_eq!(capsule.parameters().baud_rate, 115200);
+
+        let params = uart::Parameters {
+            baud_rate: 9600,
+            parity: uart::Parity::Even,
+            ..DEFAULT_PARAMETERS
+        };
+        assert_eq!(capsule.configure(params), Ok(()));
+        assert_eq!(capsule.parameters().baud_rate, 9600);
+        assert_eq!(capsule.parameters().parity, uart::Parity::Even);
+
+        // A rejected configuration leaves the recorded settings alone.
+        let bad = uart::Parameters {
+            baud_rate: 0,
+            ..DEFAULT_PARAMETERS
+        };
+        assert_eq!(capsule.configure(bad), Err(ErrorCode::INVAL));
+        assert_eq!(capsule.parameters().baud_rate, 9600);
+    }
+
+    #[test]
+    fn configure_through_uart_device() {
+        let uart = MockUart::new();
+        let mux = MuxUart::new(&uart, buffer(8), 115200);
+        let device = UartDevice::new(&mux, true);
+        let capsule = UartCapsule::new(&device, buffer(7), buffer(7), RxMode::Raw);
+
+        let params = uart::Parameters {
+            baud_rate: 9600,
+            ..DEFAULT_PARAMETERS
+        };
+        assert_eq!(capsule.configure(params), Ok(()));
+        assert_eq!(capsule.parameters().baud_rate, 9600);
+
+        // The mux's UART rejects the settings, and the capsule keeps its own.
+        let bad = uart::Parameters {
+            baud_rate: 0,
+            ..DEFAULT_PARAMETERS
+        };
+        assert_eq!(capsule.configure(bad), Err(ErrorCode::INVAL));
+        assert_eq!(capsule.parameters().baud_rate, 9600);
+    }
+
+    #[test]
+    fn ring_buffer_wraps_around() {
+        let uart = MockUart::new();
+        let capsule = UartCapsule::new(&uart, buffer(7), buffer(7), RxMode::Raw);
+        let mut out = [0u8; RX_BUF_LEN];
+
+        // Advance the indices close to the end of the ring.
+        capsule.buffer_received(&[0; RX_BUF_LEN - 3]);
+        assert_eq!(capsule.read(&mut out), RX_BUF_LEN - 3);
+
+        capsule.buffer_received(&[1, 2, 3, 4, 5, 6]);
+        assert_eq!(capsule.read(&mut out[..4]), 4);
+        assert_eq!(&out[..4], &[1, 2, 3, 4]);
+        assert_eq!(capsule.read(&mut out), 2);
+        assert_eq!(&out[..2], &[5, 6]);
+        assert_eq!(capsule.read(&mut out), 0);
+        assert_eq!(capsule.dropped(), 0);
+    }
+
+    #[test]
+    fn ring_buffer_counts_overflow() {
+        let uart = MockUart::new();
+        let capsule = UartCapsule::new(&uart, buffer(7), buffer(7), RxMode::Raw);
+        let mut out = [0u8; 2 * RX_BUF_LEN];
+
+        capsule.buffer_received(&[7; RX_BUF_LEN + 4]);
+        assert_eq!(capsule.dropped(), 5);
+        assert_eq!(capsule.read(&mut out), RX_BUF_LEN - 1);
+    }
+
+    #[test]
+    fn received_bytes_are_buffered() {
+        let uart = MockUart::new();
+        let capsule = UartCapsule::new(&uart, buffer(7), buffer(7), RxMode::Raw);
+        uart.set_receive_client(&capsule);
+
+        assert_eq!(capsule.receive(), Ok(()));
+        uart.inject_rx(&[10, 20, 30]);
+
+        let mut out = [0u8; 7];
+        assert_eq!(capsule.read(&mut out), 3);
+        assert_eq!(&out[..3], &[10, 20, 30]);
+    }
+
+    #[test]
+    fn raw_mode_leaves_received_data_untouched() {
+        let uart = MockUart::new();
+        let capsule = UartCapsule::new(&uart, buffer(7), buffer(7), RxMode::default());
+        uart.set_receive_client(&capsule);
+
+        assert_eq!(capsule.receive(), Ok(()));
+        uart.inject_rx(&[255, 1, 2]);
+
+        capsule
+            .rx_buffer
+            .map(|rx| assert_eq!(&rx[..3], &[255, 1, 2]));
+        assert!(!uart.transmitting());
+    }
+
+    #[test]
+    fn pong_mode_echoes_incremented_copy() {
+        let uart = MockUart::new();
+        let capsule = UartCapsule::new(&uart, buffer(7), buffer(7), RxMode::PongIncrement);
+        uart.set_receive_client(&capsule);
+
+        assert_eq!(capsule.receive(), Ok(()));
+        uart.inject_rx(&[255, 1, 2]);
+
+        capsule
+            .rx_buffer
+            .map(|rx| assert_eq!(&rx[..3], &[255, 1, 2]));
+        assert_eq!(uart.pending_tx().unwrap(), [0, 1, 2]);
+    }
+
+    #[test]
+    fn received_words_are_buffered() {
+        let uart = MockUart::new();
+        let capsule = UartCapsule::new(&uart, buffer(7), buffer(7), RxMode::Raw);
+        uart.set_receive_client(&capsule);
+
+        // Keep re-arming so every word is received.
+        capsule.continuous.set(true);
+        assert_eq!(capsule.receive_word(), Ok(()));
+        for &byte in b"ping" {
+            uart.inject_rx_word(byte as u32, uart::Error::None);
+        }
+        // A character received with an error is discarded.
+        uart.inject_rx_word(b'?' as u32, uart::Error::ParityError);
+        assert!(uart.rx_word_armed());
+
+        let mut out = [0; 8];
+        assert_eq!(capsule.read(&mut out), 4);
+        assert_eq!(&out[..4], b"ping");
+    }
+
+    #[test]
+    fn pong_mode_echoes_received_word() {
+        let uart = MockUart::new();
+        let capsule = UartCapsule::new(&uart, buffer(7), buffer(7), RxMode::PongIncrement);
+        uart.set_transmit_client(&capsule);
+        uart.set_receive_client(&capsule);
+
+        assert_eq!(capsule.receive_word(), Ok(()));
+        uart.inject_rx_word(b'a' as u32, uart::Error::None);
+        assert_eq!(uart.complete_tx(), b"b");
+
+        // Once the echo is sent the next word is awaited.
+        assert!(uart.rx_word_armed());
+        let mut out = [0; 2];
+        assert_eq!(capsule.read(&mut out), 1);
+        assert_eq!(out[0], b'a');
+    }
+
+    #[test]
+    fn transmitted_word_releases_transmitter() {
+        let uart = MockUart::new();
+        let capsule = UartCapsule::new(&uart, buffer(7), buffer(7), RxMode::Raw);
+        uart.set_transmit_client(&capsule);
+
+        assert_eq!(capsule.transmit_word(b'a' as u32), Ok(()));
+        assert_eq!(capsule.transmit_word(b'b' as u32), Err(ErrorCode::BUSY));
+        assert_eq!(uart.complete_tx_word(), b'a' as u32);
+        assert_eq!(capsule.transmit_word(b'b' as u32), Ok(()));
+        assert_eq!(uart.pending_tx_word(), Some(b'b' as u32));
+    }
+
+    #[test]
+    fn loopback_arms_receive_for_pattern() {
+        let uart = MockUart::new();
+        let capsule = UartCapsule::new(&uart, buffer(7), buffer(7), RxMode::Raw);
+
+        assert_eq!(capsule.run_loopback(&[0; 8]), Err(ErrorCode::SIZE));
+        assert_eq!(capsule.run_loopback(&[0x55, 0xaa, 0x0f]), Ok(()));
+        assert_eq!(uart.pending_rx_len(), Some(3));
+        assert_eq!(uart.pending_tx().unwrap(), [0x55, 0xaa, 0x0f]);
+        assert_eq!(capsule.run_loopback(&[1]), Err(ErrorCode::BUSY));
+
+        assert!(capsule.loopback_matches(&[0x55, 0xaa, 0x0f]));
+        assert!(!capsule.loopback_matches(&[0x55, 0xaa, 0x00]));
+        assert!(!capsule.loopback_matches(&[0x55, 0xaa]));
+    }
+
+    #[test]
+    fn loopback_passes_on_matching_echo() {
+        let uart = MockUart::new();
+        let capsule = UartCapsule::new(&uart, buffer(7), buffer(7), RxMode::Raw);
+        uart.set_transmit_client(&capsule);
+        uart.set_receive_client(&capsule);
+
+        assert_eq!(capsule.run_loopback(&[0x55, 0xaa, 0x0f]), Ok(()));
+        assert_eq!(uart.complete_tx(), [0x55, 0xaa, 0x0f]);
+        assert_eq!(capsule.loopback_result(), None);
+        uart.inject_rx(&[0x55, 0xaa, 0x0f]);
+        assert_eq!(capsule.loopback_result(), Some(Ok(())));
+
+        // The echo is not kept as received data, and another test can run.
+        assert_eq!(capsule.read(&mut [0; 7]), 0);
+        assert_eq!(capsule.run_loopback(&[1]), Ok(()));
+        assert_eq!(capsule.loopback_result(), None);
+    }
+
+    #[test]
+    fn loopback_fails_on_mismatched_echo() {
+        let uart = MockUart::new();
+        let capsule = UartCapsule::new(&uart, buffer(7), buffer(7), RxMode::Raw);
+        uart.set_transmit_client(&capsule);
+        uart.set_receive_client(&capsule);
+
+        assert_eq!(capsule.run_loopback(&[0x55, 0xaa, 0x0f]), Ok(()));
+        assert_eq!(uart.complete_tx(), [0x55, 0xaa, 0x0f]);
+        uart.inject_rx(&[0x55, 0xaa, 0x00]);
+        assert_eq!(capsule.loopback_result(), Some(Err(ErrorCode::FAIL)));
+    }
+
+    #[test]
+    fn loopback_too_long_for_tx_buffer_arms_nothing() {
+        let uart = MockUart::new();
+        let capsule = UartCapsule::new(&uart, buffer(2), buffer(7), RxMode::Raw);
+        uart.set_receive_client(&capsule);
+
+        assert_eq!(capsule.run_loopback(&[1, 2, 3]), Err(ErrorCode::SIZE));
+        assert_eq!(uart.pending_rx_len(), None);
+        assert_eq!(capsule.loopback_result(), None);
+        assert_eq!(capsule.receive(), Ok(()));
+    }
+
+    #[test]
+    fn loopback_transmit_failure_cancels_receive() {
+        let uart = MockUart::new();
+        let capsule = UartCapsule::new(&uart, buffer(7), buffer(7), RxMode::Raw);
+        uart.set_transmit_client(&capsule);
+        uart.set_receive_client(&capsule);
+
+        // Keep the device busy behind the capsule's back.
+        assert!(uart.transmit_buffer(buffer(1), 1).is_ok());
+        assert_eq!(capsule.run_loopback(&[1, 2, 3]), Err(ErrorCode::BUSY));
+
+        // The armed receive was cancelled and the test has ended.
+        assert_eq!(uart.pending_rx_len(), None);
+        assert_eq!(capsule.loopback_result(), Some(Err(ErrorCode::CANCEL)));
+        assert_eq!(capsule.receive(), Ok(()));
+    }
+
+    #[test]
+    fn flow_control_sends_xoff_then_xon() {
+        let uart = MockUart::new();
+        let capsule = UartCapsule::new(&uart, buffer(20), buffer(20), RxMode::Raw);
+        uart.set_transmit_client(&capsule);
+        uart.set_receive_client(&capsule);
+        capsule.continuous.set(true);
+
+        assert_eq!(capsule.enable_flow_control(4, 4), Err(ErrorCode::INVAL));
+        assert_eq!(
+            capsule.enable_flow_control(RX_BUF_LEN, 4),
+            Err(ErrorCode::INVAL)
+        );
+        assert_eq!(capsule.enable_flow_control(8, 2), Ok(()));
+
+        assert_eq!(capsule.receive(), Ok(()));
+        uart.inject_rx(&[0; 7]);
+        assert!(!uart.transmitting());
+        uart.inject_rx(&[0; 3]);
+        assert_eq!(uart.pending_tx().unwrap(), [XOFF]);
+
+        // Draining the ring queues XON behind the XOFF.
+        let mut out = [0u8; 9];
+        assert_eq!(capsule.read(&mut out), 9);
+        assert_eq!(uart.complete_tx(), [XOFF]);
+        assert_eq!(uart.complete_tx(), [XON]);
+        assert!(!uart.transmitting());
+    }
+
+    #[test]
+    fn flow_control_is_off_by_default() {
+        let uart = MockUart::new();
+        let capsule = UartCapsule::new(&uart, buffer(20), buffer(20), RxMode::Raw);
+        uart.set_receive_client(&capsule);
+        capsule.continuous.set(true);
+
+        assert_eq!(capsule.receive(), Ok(()));
+        for _ in 0..3 {
+            uart.inject_rx(&[0; 20]);
+        }
+        assert!(!uart.transmitting());
+    }
+
+    #[test]
+    fn transmit_sync_bypasses_queue() {
+        let uart = MockUart::new();
+        let capsule = UartCapsule::new(&uart, buffer(20), buffer(20), RxMode::Raw);
+        uart.set_transmit_client(&capsule);
+
+        assert_eq!(capsule.start_transmission(&[1, 2]), Ok(()));
+        assert_eq!(capsule.start_transmission(&[3]), Ok(()));
+        capsule.transmit_sync(b"panic");
+        assert_eq!(uart.transmitted(), b"panic");
+
+        // The interrupt-driven transmits are left as they were.
+        assert_eq!(uart.complete_tx(), [1, 2]);
+        assert_eq!(uart.complete_tx(), [3]);
+    }
+
+    #[test]
+    fn transmit_sync_through_uart_device() {
+        let uart = MockUart::new();
+        let mux = MuxUart::new(&uart, buffer(8), 115200);
+        let device = UartDevice::new(&mux, true);
+        let capsule = UartCapsule::new(&device, buffer(7), buffer(7), RxMode::Raw);
+
+        // Without a polled path on the mux the bytes are dropped.
+        capsule.transmit_sync(b"lost");
+        assert!(uart.transmitted().is_empty());
+
+        mux.set_transmit_sync(&uart);
+        capsule.transmit_sync(b"panic");
+        assert_eq!(uart.transmitted(), b"panic");
+    }
+
+    #[test]
+    fn back_to_back_receive_is_rejected() {
+        let uart = MockUart::new();
+        let capsule = UartCapsule::new(&uart, buffer(20), buffer(20), RxMode::Raw);
+
+        assert_eq!(capsule.receive(), Ok(()));
+        assert_eq!(capsule.receive(), Err(ErrorCode::BUSY));
+    }
 }
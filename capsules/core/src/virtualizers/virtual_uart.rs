@@ -58,6 +58,7 @@ pub const RX_BUF_LEN: usize = 64;
 
 pub struct MuxUart<'a> {
     uart: &'a dyn uart::Uart<'a>,
+    transmit_sync: OptionalCell<&'a dyn uart::TransmitSync>,
     speed: u32,
     devices: List<'a, UartDevice<'a>>,
     inflight: OptionalCell<&'a UartDevice<'a>>,
@@ -214,6 +215,7 @@ impl<'a> MuxUart<'a> {
     pub fn new(uart: &'a dyn uart::Uart<'a>, buffer: &'static mut [u8], speed: u32) -> MuxUart<'a> {
         MuxUart {
             uart,
+            transmit_sync: OptionalCell::empty(),
             speed,
             devices: List::new(),
             inflight: OptionalCell::empty(),
@@ -223,6 +225,13 @@ impl<'a> MuxUart<'a> {
         }
     }
 
+    /// Set the polled transmit path of the UART behind this mux, which
+    /// `UartDevice`s use for `uart::TransmitSync`. It must be the same
+    /// hardware as the `uart` passed to `new`.
+    pub fn set_transmit_sync(&self, transmit_sync: &'a dyn uart::TransmitSync) {
+        self.transmit_sync.set(transmit_sync);
+    }
+
     pub fn initialize(&self) {
         let _ = self.uart.configure(uart::Parameters {
             baud_rate: self.speed,
@@ -455,6 +464,26 @@ impl<'a> uart::Transmit<'a> for UartDevice<'a> {
     }
 }
 
+/// Configuring a `UartDevice` changes the line settings of the UART behind
+/// its `MuxUart`, which every device on that mux shares. It should only be
+/// done while no other device has a transfer in progress.
+impl<'a> uart::Configure for UartDevice<'a> {
+    fn configure(&self, params: uart::Parameters) -> Result<(), ErrorCode> {
+        self.mux.uart.configure(params)
+    }
+}
+
+/// Polls the UART behind the mux, bypassing its queue and any transmit in
+/// flight. Bytes are dropped if the board did not call
+/// `MuxUart::set_transmit_sync`.
+impl<'a> uart::TransmitSync for UartDevice<'a> {
+    fn transmit_byte_sync(&self, byte: u8) {
+        self.mux
+            .transmit_sync
+            .map(|transmit_sync| transmit_sync.transmit_byte_sync(byte));
+    }
+}
+
 impl<'a> uart::Receive<'a> for UartDevice<'a> {
     fn set_receive_client(&self, client: &'a dyn uart::ReceiveClient) {
         self.rx_client.set(client);
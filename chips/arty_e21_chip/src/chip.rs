@@ -201,8 +201,13 @@ impl<'a, I: InterruptService + 'a> kernel::platform::chip::Chip for ArtyExx<'a,
 /// For the arty-e21 this gets called when an interrupt occurs while the chip is
 /// in kernel mode. All we need to do is check which interrupt occurred and
 /// disable it.
+///
+/// `stack_overflow` is non-zero if `_start_trap` found the kernel stack
+/// overflowed.
 #[export_name = "_start_trap_rust_from_kernel"]
-pub extern "C" fn start_trap_rust() {
+pub extern "C" fn start_trap_rust(stack_overflow: usize) {
+    rv32i::check_kernel_stack_overflow(stack_overflow);
+
     let mcause = rv32i::csr::CSR.mcause.extract();
 
     match rv32i::csr::mcause::Trap::from(mcause) {
@@ -218,7 +223,7 @@ pub extern "C" fn start_trap_rust() {
 
         rv32i::csr::mcause::Trap::Exception(_exception) => {
             // Otherwise, the kernel encountered a fault...so panic!()?
-            panic!("kernel exception");
+            rv32i::kernel_fault(format_args!("kernel exception"));
         }
     }
 }
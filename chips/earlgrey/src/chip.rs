@@ -342,7 +342,11 @@ fn handle_exception(exception: mcause::Exception) {
         | mcause::Exception::LoadPageFault
         | mcause::Exception::StorePageFault
         | mcause::Exception::Unknown => {
-            panic!("fatal exception: {:?}: {:#x}", exception, CSR.mtval.get());
+            rv32i::kernel_fault(format_args!(
+                "fatal exception: {:?}: {:#x}",
+                exception,
+                CSR.mtval.get()
+            ));
         }
     }
 }
@@ -400,8 +404,13 @@ unsafe fn handle_interrupt(intr: mcause::Interrupt) {
 ///
 /// For the Ibex this gets called when an interrupt occurs while the chip is
 /// in kernel mode.
+///
+/// `stack_overflow` is non-zero if `_start_trap` found the kernel stack
+/// overflowed.
 #[export_name = "_start_trap_rust_from_kernel"]
-pub unsafe extern "C" fn start_trap_rust() {
+pub unsafe extern "C" fn start_trap_rust(stack_overflow: usize) {
+    rv32i::check_kernel_stack_overflow(stack_overflow);
+
     match mcause::Trap::from(CSR.mcause.extract()) {
         mcause::Trap::Interrupt(interrupt) => {
             handle_interrupt(interrupt);
@@ -472,6 +472,15 @@ impl<'a> uart::Transmit<'a> for Uarte<'a> {
     }
 }
 
+impl uart::TransmitSync for Uarte<'_> {
+    fn transmit_byte_sync(&self, byte: u8) {
+        unsafe {
+            self.send_byte(byte);
+        }
+        while !self.tx_ready() {}
+    }
+}
+
 impl<'a> uart::Configure for Uarte<'a> {
     fn configure(&self, params: uart::Parameters) -> Result<(), ErrorCode> {
         // These could probably be implemented, but are currently ignored, so
@@ -162,6 +162,19 @@ pub trait Transmit<'a> {
     fn transmit_abort(&self) -> Result<(), ErrorCode>;
 }
 
+/// Trait for sending bytes by polling the hardware, without interrupts or
+/// client callbacks.
+///
+/// This is meant for contexts where the interrupt-driven `Transmit` path
+/// cannot make progress, such as a panic handler or very early boot. It must
+/// only be used while interrupts are disabled or otherwise not being
+/// serviced, as it drives the hardware behind the back of any transmit in
+/// progress.
+pub trait TransmitSync {
+    /// Send `byte` and busy-wait until the hardware has finished with it.
+    fn transmit_byte_sync(&self, byte: u8);
+}
+
 pub trait Receive<'a> {
     /// Set the receive client, which will he called when reads complete.
     fn set_receive_client(&self, client: &'a dyn ReceiveClient);
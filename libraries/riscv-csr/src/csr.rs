@@ -17,6 +17,11 @@ pub const MCYCLE: usize = 0xB00;
 pub const MIE: usize = 0x304;
 pub const MTVEC: usize = 0x305;
 pub const MSTATUS: usize = 0x300;
+pub const MISA: usize = 0x301;
+pub const MEDELEG: usize = 0x302;
+pub const MIDELEG: usize = 0x303;
+pub const MCOUNTEREN: usize = 0x306;
+pub const MCOUNTINHIBIT: usize = 0x320;
 pub const UTVEC: usize = 0x005;
 pub const STVEC: usize = 0x105;
 pub const MSCRATCH: usize = 0x340;